@@ -0,0 +1,32 @@
+//! fastp-rs with an in-house stage: reads starting with a linker lose it.
+//!
+//! cargo run --release --example linker_stage -- -i in.fq -o out.fq \
+//!     --trim_order fixed,linker,quality,overlap,poly_g,adapter,poly_x,mask,max_len
+
+use std::io;
+use clap::Parser;
+use fastp_rs::{run, Cli, Processor, Registry, Report, TrimState};
+
+const LINKER: &str = "CTGTCTCTTATA";
+
+struct LinkerTrimmer;
+
+impl Processor for LinkerTrimmer {
+    fn name(&self) -> &'static str {
+        "linker"
+    }
+
+    fn process(&self, state: &mut TrimState, _report: &mut Report) {
+        for read in state.reads_mut() {
+            if read.rec.seq.starts_with(LINKER) {
+                read.cut_front(LINKER.len());
+            }
+        }
+    }
+}
+
+fn main() -> io::Result<()> {
+    let mut registry = Registry::builtin();
+    registry.register("linker", |_| Ok(Box::new(LinkerTrimmer)));
+    run(Cli::parse(), &registry)
+}
@@ -0,0 +1,153 @@
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+use std::fs::File;
+use std::io::{self, Write, BufWriter};
+
+/// Uncompressed payload per BGZF block.
+/// htslib uses 0xff00 so that even incompressible data fits in the 64 KiB block limit.
+pub const BGZF_BLOCK_SIZE: usize = 0xff00;
+
+/// Hard limit on the size of one compressed BGZF block (BSIZE is a u16 holding size - 1).
+const BGZF_MAX_BLOCK_SIZE: usize = 0x10000;
+
+/// Gzip header (18 bytes) + CRC32/ISIZE trailer (8 bytes).
+const BGZF_HEADER_SIZE: usize = 18;
+const BGZF_FOOTER_SIZE: usize = 8;
+
+/// The empty block htslib appends to mark a complete file.
+pub const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00,
+];
+
+/// Compress `data` (at most `BGZF_BLOCK_SIZE` bytes) into a single BGZF block:
+/// a gzip member carrying the `BC` extra subfield with the total block size.
+pub fn compress_block(data: &[u8], level: u32) -> io::Result<Vec<u8>> {
+    debug_assert!(data.len() <= BGZF_BLOCK_SIZE);
+    let mut cdata = deflate(data, Compression::new(level))?;
+    if cdata.len() + BGZF_HEADER_SIZE + BGZF_FOOTER_SIZE > BGZF_MAX_BLOCK_SIZE {
+        // incompressible input: stored deflate blocks always fit
+        cdata = deflate(data, Compression::none())?;
+    }
+    let bsize = cdata.len() + BGZF_HEADER_SIZE + BGZF_FOOTER_SIZE;
+
+    let mut crc = Crc::new();
+    crc.update(data);
+
+    let mut out = Vec::with_capacity(bsize);
+    out.extend_from_slice(&[
+        0x1f, 0x8b, // ID1, ID2
+        0x08,       // CM = deflate
+        0x04,       // FLG = FEXTRA
+        0x00, 0x00, 0x00, 0x00, // MTIME
+        0x00,       // XFL
+        0xff,       // OS = unknown
+        0x06, 0x00, // XLEN
+        b'B', b'C', // SI1, SI2
+        0x02, 0x00, // SLEN
+    ]);
+    out.extend_from_slice(&((bsize - 1) as u16).to_le_bytes());
+    out.extend_from_slice(&cdata);
+    out.extend_from_slice(&crc.sum().to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    Ok(out)
+}
+
+fn deflate(data: &[u8], level: Compression) -> io::Result<Vec<u8>> {
+    let mut enc = DeflateEncoder::new(Vec::with_capacity(data.len() / 2), level);
+    enc.write_all(data)?;
+    enc.finish()
+}
+
+/// Block offsets of a BGZF file, in the `.gzi` layout used by `bgzip -i`:
+/// a little-endian u64 entry count followed by (compressed, uncompressed) u64 offset pairs.
+/// The implicit first block at (0, 0) is not stored.
+#[derive(Default)]
+pub struct BgzfIndex {
+    entries: Vec<(u64, u64)>,
+    compressed_offset: u64,
+    uncompressed_offset: u64,
+}
+
+impl BgzfIndex {
+    pub fn add_block(&mut self, compressed_len: usize, uncompressed_len: usize) {
+        self.compressed_offset += compressed_len as u64;
+        self.uncompressed_offset += uncompressed_len as u64;
+        self.entries.push((self.compressed_offset, self.uncompressed_offset));
+    }
+
+    pub fn write_to(&self, path: &str) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        w.write_all(&(self.entries.len() as u64).to_le_bytes())?;
+        for (c, u) in &self.entries {
+            w.write_all(&c.to_le_bytes())?;
+            w.write_all(&u.to_le_bytes())?;
+        }
+        w.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::MultiGzDecoder;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::io::Read;
+
+    fn gunzip(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        MultiGzDecoder::new(data).read_to_end(&mut out).unwrap();
+        out
+    }
+
+    fn block_size(block: &[u8]) -> usize {
+        u16::from_le_bytes([block[16], block[17]]) as usize + 1
+    }
+
+    #[test]
+    fn blocks_are_gzip_members_with_their_size() {
+        let data = b"@r1\nACGTACGTACGT\n+\nIIIIIIIIIIII\n".repeat(1000);
+        let block = compress_block(&data, 6).unwrap();
+        assert_eq!(&block[12..14], b"BC");
+        assert_eq!(block_size(&block), block.len());
+        assert_eq!(&block[block.len() - 4..], &(data.len() as u32).to_le_bytes());
+        // blocks and the EOF marker concatenate into one valid gzip stream
+        let mut file = block.clone();
+        file.extend_from_slice(&block);
+        file.extend_from_slice(&BGZF_EOF);
+        assert_eq!(gunzip(&file), [data.as_slice(), &data].concat());
+    }
+
+    #[test]
+    fn incompressible_data_fits_one_block() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let data: Vec<u8> = (0..BGZF_BLOCK_SIZE).map(|_| rng.random()).collect();
+        let block = compress_block(&data, 9).unwrap();
+        assert!(block.len() <= BGZF_MAX_BLOCK_SIZE);
+        assert_eq!(block_size(&block), block.len());
+        assert_eq!(gunzip(&block), data);
+    }
+
+    #[test]
+    fn eof_marker_is_an_empty_block() {
+        assert_eq!(block_size(&BGZF_EOF), BGZF_EOF.len());
+        assert!(gunzip(&BGZF_EOF).is_empty());
+    }
+
+    #[test]
+    fn index_lists_block_ends() {
+        let mut index = BgzfIndex::default();
+        index.add_block(100, 0xff00);
+        index.add_block(80, 0xff00);
+        index.add_block(10, 20);
+        let path = std::env::temp_dir().join(format!("fastp_rs_bgzf_test_{}.gzi", std::process::id()));
+        index.write_to(path.to_str().unwrap()).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let words: Vec<u64> = bytes.chunks(8).map(|c| u64::from_le_bytes(c.try_into().unwrap())).collect();
+        assert_eq!(words, [3, 100, 0xff00, 180, 0x1fe00, 190, 0x1fe14]);
+    }
+}
@@ -0,0 +1,109 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+use super::{BlockFormat, CompressOptions};
+
+/// An external compressor (`pigz`, `bgzip` or `zstd`) fed through a pipe,
+/// writing its compressed stream straight to the output file.
+pub struct ExternalCompressor {
+    program: &'static str,
+    child: Child,
+    stdin: Option<BufWriter<ChildStdin>>,
+}
+
+impl ExternalCompressor {
+    /// Spawn the compressor for `format` with `threads` threads.
+    /// Output goes to `path`, or to our own stdout when `path` is `None`.
+    /// For BGZF with `index`, bgzip also writes `<path>.gzi`.
+    pub fn spawn(path: Option<&str>, format: BlockFormat, opts: &CompressOptions, threads: usize, index: bool) -> io::Result<Self> {
+        let threads = threads.max(1).to_string();
+        let (program, mut cmd) = match format {
+            BlockFormat::Gzip => {
+                let mut cmd = Command::new("pigz");
+                cmd.arg("-c").arg("-p").arg(&threads).arg(format!("-{}", opts.level.min(9)));
+                ("pigz", cmd)
+            }
+            BlockFormat::Bgzf => {
+                let mut cmd = Command::new("bgzip");
+                cmd.arg("-c").arg("-@").arg(&threads).arg("-l").arg(opts.level.min(9).to_string());
+                if let (true, Some(p)) = (index, path) {
+                    cmd.arg("-i").arg("-I").arg(format!("{}.gzi", p));
+                }
+                ("bgzip", cmd)
+            }
+            BlockFormat::Zstd => {
+                let mut cmd = Command::new("zstd");
+                cmd.arg("-c").arg("-q").arg(format!("-T{}", threads)).arg(format!("-{}", opts.zstd_level));
+                if opts.zstd_level > 19 {
+                    cmd.arg("--ultra");
+                }
+                if opts.zstd_long {
+                    cmd.arg("--long=27");
+                }
+                ("zstd", cmd)
+            }
+        };
+        let stdout = match path {
+            Some(p) => Stdio::from(File::create(p)?),
+            None => Stdio::inherit(),
+        };
+        let mut child = cmd
+            .stdin(Stdio::piped())
+            .stdout(stdout)
+            .spawn()
+            .map_err(|e| io::Error::new(e.kind(), format!("failed to start {}: {}", program, e)))?;
+        let stdin = child.stdin.take().map(|s| BufWriter::with_capacity(1 << 20, s));
+        Ok(Self { program, child, stdin })
+    }
+
+    /// Close the pipe and wait for the compressor; a non-zero exit becomes an error.
+    pub fn finish(mut self) -> io::Result<()> {
+        let flushed = match self.stdin.take() {
+            Some(mut s) => s.flush(),
+            None => Ok(()),
+        };
+        let status = self.child.wait()?;
+        if !status.success() {
+            return Err(io::Error::other(format!("{} exited with {}", self.program, status)));
+        }
+        flushed
+    }
+
+    /// A broken pipe means the child died; report its exit status instead.
+    fn check_pipe(&mut self, err: io::Error) -> io::Error {
+        if err.kind() == io::ErrorKind::BrokenPipe {
+            self.stdin = None;
+            if let Ok(status) = self.child.wait() {
+                return io::Error::other(format!("{} exited with {}", self.program, status));
+            }
+        }
+        err
+    }
+}
+
+impl Write for ExternalCompressor {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let res = match &mut self.stdin {
+            Some(s) => s.write(buf),
+            None => Err(io::Error::new(io::ErrorKind::BrokenPipe, "compressor input closed")),
+        };
+        res.map_err(|e| self.check_pipe(e))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let res = match &mut self.stdin {
+            Some(s) => s.flush(),
+            None => Ok(()),
+        };
+        res.map_err(|e| self.check_pipe(e))
+    }
+}
+
+impl Drop for ExternalCompressor {
+    fn drop(&mut self) {
+        // close the pipe so the child sees EOF and is not left running
+        self.stdin = None;
+        let _ = self.child.wait();
+    }
+}
@@ -1,48 +1,358 @@
-use crossbeam::channel::{unbounded, Sender, Receiver};
+mod bgzf;
+mod external;
+mod parallel_read;
+
+use crossbeam::channel::{bounded, unbounded, Sender, Receiver};
 use flate2::Compression;
+use flate2::read::MultiGzDecoder;
 use flate2::write::GzEncoder;
-use std::io::Write;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::Arc;
+use crate::metrics::Metrics;
+use crate::scheduler::Gate;
 
-pub struct CompressTask {
-    pub id: u64,
-    pub which: u8, // 1 for R1, 2 for R2
-    pub data: Vec<u8>,
+pub use self::bgzf::{BgzfIndex, BGZF_BLOCK_SIZE, BGZF_EOF};
+pub use self::external::ExternalCompressor;
+
+/// Uncompressed bytes per plain gzip member.
+/// Concatenated members are still a valid gzip stream, larger members keep the ratio close to single-stream gzip.
+const GZIP_MEMBER_SIZE: usize = 1 << 20;
+
+/// Uncompressed bytes per zstd frame; with long-distance matching the frame covers the whole match window.
+/// Long frames stay small enough for 16 of them to be in flight within `MAX_PENDING_BYTES`.
+const ZSTD_FRAME_SIZE: usize = 4 << 20;
+const ZSTD_LONG_FRAME_SIZE: usize = 1 << ZSTD_LONG_WINDOW_LOG;
+
+/// Cap on uncompressed bytes queued per writer; large zstd frames leave fewer blocks in flight than threads.
+const MAX_PENDING_BYTES: usize = 512 << 20;
+
+/// 32 MiB window: frames are compressed independently, so a window past one frame finds nothing more.
+const ZSTD_LONG_WINDOW_LOG: u32 = 25;
+
+/// Compression format of an input stream, detected from its magic bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputFormat {
+    Plain,
+    Gzip,
+    /// gzip whose members carry the BGZF `BC` extra field
+    Bgzf,
+    Bzip2,
+    Xz,
+    Zstd,
+}
+
+impl InputFormat {
+    pub fn detect(magic: &[u8]) -> Self {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            let has_extra = magic.len() >= 12 && magic[3] & 0x04 != 0;
+            let xlen = if has_extra { u16::from_le_bytes([magic[10], magic[11]]) as usize } else { 0 };
+            if has_extra && magic.len() >= 12 + xlen && parallel_read::bgzf_block_size(&magic[12..12 + xlen]).is_some() {
+                InputFormat::Bgzf
+            } else {
+                InputFormat::Gzip
+            }
+        } else if magic.starts_with(b"BZh") {
+            InputFormat::Bzip2
+        } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            InputFormat::Xz
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            InputFormat::Zstd
+        } else {
+            InputFormat::Plain
+        }
+    }
+}
+
+/// Wrap `raw` in the decoder matching its leading magic bytes, regardless of file name.
+/// Concatenated members/streams/frames are all decoded.
+/// Decoding runs off the calling thread: BGZF blocks are inflated on `threads` threads,
+/// other compressed streams on one pipelined decoder thread that reads ahead of the caller.
+pub fn open_decoder<R: Read + Send + 'static>(raw: R, threads: usize) -> io::Result<(Box<dyn BufRead + Send>, InputFormat)> {
+    let mut buffered = BufReader::new(raw);
+    let format = InputFormat::detect(buffered.fill_buf()?);
+    let decoder: Box<dyn BufRead + Send> = match format {
+        InputFormat::Plain => return Ok((Box::new(buffered), format)),
+        InputFormat::Bgzf => return Ok((Box::new(parallel_read::bgzf(buffered, threads)), format)),
+        InputFormat::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(buffered))),
+        InputFormat::Bzip2 => Box::new(BufReader::new(bzip2::bufread::MultiBzDecoder::new(buffered))),
+        InputFormat::Xz => Box::new(BufReader::new(xz2::bufread::XzDecoder::new_multi_decoder(buffered))),
+        InputFormat::Zstd => Box::new(BufReader::new(zstd::stream::read::Decoder::with_buffer(buffered)?)),
+    };
+    Ok((Box::new(parallel_read::pipelined(decoder)), format))
+}
+
+/// Compression settings shared by all pool threads.
+#[derive(Clone, Copy, Debug)]
+pub struct CompressOptions {
+    /// gzip/BGZF level, 0..=9
+    pub level: u32,
+    pub zstd_level: i32,
+    /// zstd long-distance matching over a 128 MiB window
+    pub zstd_long: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockFormat {
+    /// independent gzip members
+    Gzip,
+    /// 64 KiB BGZF blocks, readable by htslib/samtools and indexable with `.gzi`
+    Bgzf,
+    /// independent zstd frames
+    Zstd,
+}
+
+impl BlockFormat {
+    /// Pick the block format for an output path; `None` means the output is not compressed.
+    pub fn for_path(path: &str, bgzf: bool) -> Option<Self> {
+        if path.ends_with(".zst") {
+            Some(BlockFormat::Zstd)
+        } else if path.ends_with(".bgz") || path.ends_with(".bam") || (bgzf && path.ends_with(".gz")) {
+            Some(BlockFormat::Bgzf)
+        } else if path.ends_with(".gz") {
+            Some(BlockFormat::Gzip)
+        } else {
+            None
+        }
+    }
+
+    pub fn block_size(self, opts: &CompressOptions) -> usize {
+        match self {
+            BlockFormat::Gzip => GZIP_MEMBER_SIZE,
+            BlockFormat::Bgzf => BGZF_BLOCK_SIZE,
+            BlockFormat::Zstd if opts.zstd_long => ZSTD_LONG_FRAME_SIZE,
+            BlockFormat::Zstd => ZSTD_FRAME_SIZE,
+        }
+    }
 }
 
-pub struct CompressedResult {
-    pub id: u64,
-    pub which: u8,
+pub struct CompressTask {
+    pub format: BlockFormat,
     pub data: Vec<u8>,
+    pub reply: Sender<io::Result<Vec<u8>>>,
 }
 
 pub struct CompressionPool {
     tx: Sender<CompressTask>,
-    pub rx: Receiver<CompressedResult>,
-    level: u32,
+    threads: usize,
+    opts: CompressOptions,
 }
 
 impl CompressionPool {
-    pub fn new(threads: usize, level: u32) -> Self {
+    /// Busy and idle time of the threads goes to `metrics.compression`; only the threads
+    /// `gate` lets in take blocks.
+    pub fn new(threads: usize, opts: CompressOptions, metrics: Arc<Metrics>, gate: Arc<Gate>) -> Self {
+        let threads = threads.max(1);
         let (tx, worker_rx) = unbounded::<CompressTask>();
-        let (worker_tx, rx) = unbounded::<CompressedResult>();
-        for _ in 0..threads.max(1) {
+        for index in 0..threads {
             let rx = worker_rx.clone();
-            let tx = worker_tx.clone();
-            let lvl = level;
-            std::thread::spawn(move || {
-                while let Ok(task) = rx.recv() {
-                    let mut enc = GzEncoder::new(Vec::new(), Compression::new(lvl));
-                    // write all bytes
-                    let _ = enc.write_all(&task.data);
-                    let out = enc.finish().unwrap_or_default();
-                    let _ = tx.send(CompressedResult { id: task.id, which: task.which, data: out });
-                }
+            let metrics = metrics.clone();
+            let gate = gate.clone();
+            std::thread::spawn(move || loop {
+                metrics.compression.blocked(|| gate.wait_turn(index));
+                let Ok(task) = metrics.compression.blocked(|| rx.recv()) else { break };
+                let out = metrics.compression.busy(|| compress(task.format, &task.data, &opts));
+                metrics.compression.add_item();
+                let _ = task.reply.send(out);
             });
         }
-        Self { tx, rx, level }
+        Self { tx, threads, opts }
     }
 
-    pub fn submit(&self, id: u64, which: u8, data: Vec<u8>) {
-        let _ = self.tx.send(CompressTask { id, which, data });
+    /// Queue one block; the compressed bytes arrive on the returned receiver.
+    pub fn submit(&self, format: BlockFormat, data: Vec<u8>) -> Receiver<io::Result<Vec<u8>>> {
+        let (reply, rx) = bounded(1);
+        let _ = self.tx.send(CompressTask { format, data, reply });
+        rx
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    pub fn options(&self) -> &CompressOptions {
+        &self.opts
+    }
+}
+
+fn compress(format: BlockFormat, data: &[u8], opts: &CompressOptions) -> io::Result<Vec<u8>> {
+    match format {
+        BlockFormat::Gzip => {
+            let mut enc = GzEncoder::new(Vec::with_capacity(data.len() / 2), Compression::new(opts.level));
+            enc.write_all(data)?;
+            enc.finish()
+        }
+        BlockFormat::Bgzf => bgzf::compress_block(data, opts.level),
+        BlockFormat::Zstd => {
+            let mut enc = zstd::stream::write::Encoder::new(Vec::with_capacity(data.len() / 4), opts.zstd_level)?;
+            if opts.zstd_long {
+                enc.long_distance_matching(true)?;
+                enc.window_log(ZSTD_LONG_WINDOW_LOG)?;
+            }
+            enc.set_pledged_src_size(Some(data.len() as u64))?;
+            enc.write_all(data)?;
+            enc.finish()
+        }
+    }
+}
+
+/// Blocks a writer keeps queued: four per compression thread, within `MAX_PENDING_BYTES` (at least one).
+fn max_pending(threads: usize, block_size: usize) -> usize {
+    (threads * 4).min((MAX_PENDING_BYTES / block_size).max(1))
+}
+
+/// Splits a byte stream into blocks, compresses them on a shared `CompressionPool`
+/// and writes the results to `inner` in submission order.
+pub struct BlockWriter<W: Write> {
+    inner: W,
+    pool: Arc<CompressionPool>,
+    format: BlockFormat,
+    block_size: usize,
+    buf: Vec<u8>,
+    // (uncompressed length, pending result) in submission order
+    pending: VecDeque<(usize, Receiver<io::Result<Vec<u8>>>)>,
+    max_pending: usize,
+    index: Option<(String, BgzfIndex)>,
+}
+
+impl<W: Write> BlockWriter<W> {
+    pub fn new(inner: W, pool: Arc<CompressionPool>, format: BlockFormat) -> Self {
+        // keep every compression thread busy without buffering the whole output in memory
+        let block_size = format.block_size(pool.options());
+        let max_pending = max_pending(pool.threads(), block_size);
+        Self {
+            inner,
+            pool,
+            format,
+            block_size,
+            buf: Vec::new(),
+            pending: VecDeque::new(),
+            max_pending,
+            index: None,
+        }
+    }
+
+    /// Record block offsets and write them to `path` (`.gzi` layout) on `finish`. Only meaningful for BGZF.
+    pub fn with_index(mut self, path: String) -> Self {
+        self.index = Some((path, BgzfIndex::default()));
+        self
+    }
+
+    fn submit_full_blocks(&mut self) -> io::Result<()> {
+        while self.buf.len() >= self.block_size {
+            let rest = self.buf.split_off(self.block_size);
+            let block = std::mem::replace(&mut self.buf, rest);
+            self.submit(block)?;
+        }
+        Ok(())
+    }
+
+    fn submit(&mut self, block: Vec<u8>) -> io::Result<()> {
+        let len = block.len();
+        let rx = self.pool.submit(self.format, block);
+        self.pending.push_back((len, rx));
+        // write out whatever is already done; block only when too much is in flight
+        self.drain(false)?;
+        while self.pending.len() > self.max_pending {
+            self.write_front()?;
+        }
+        Ok(())
+    }
+
+    fn drain(&mut self, wait: bool) -> io::Result<()> {
+        while let Some((_, rx)) = self.pending.front() {
+            if !wait && rx.is_empty() {
+                break;
+            }
+            self.write_front()?;
+        }
+        Ok(())
+    }
+
+    fn write_front(&mut self) -> io::Result<()> {
+        if let Some((len, rx)) = self.pending.pop_front() {
+            let data = rx
+                .recv()
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "compression thread exited"))??;
+            self.inner.write_all(&data)?;
+            if let Some((_, idx)) = &mut self.index {
+                idx.add_block(data.len(), len);
+            }
+        }
+        Ok(())
+    }
+
+    /// Compress the remaining bytes, wait for all blocks and terminate the stream (BGZF EOF marker, `.gzi` index).
+    pub fn finish(mut self) -> io::Result<W> {
+        self.submit_full_blocks()?;
+        if !self.buf.is_empty() {
+            let block = std::mem::take(&mut self.buf);
+            self.submit(block)?;
+        }
+        self.drain(true)?;
+        if self.format == BlockFormat::Bgzf {
+            self.inner.write_all(&BGZF_EOF)?;
+        }
+        self.inner.flush()?;
+        if let Some((path, idx)) = &self.index {
+            idx.write_to(path)?;
+        }
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for BlockWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // grow as data arrives, but not past the block size: queued blocks carry no spare capacity
+        let needed = self.buf.len() + buf.len();
+        if needed > self.buf.capacity() {
+            let capacity = (self.buf.capacity() * 2).min(self.block_size).max(needed);
+            self.buf.reserve_exact(capacity - self.buf.len());
+        }
+        self.buf.extend_from_slice(buf);
+        self.submit_full_blocks()?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.drain(false)?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pending_blocks_stay_within_the_byte_cap() {
+        assert_eq!(max_pending(8, BGZF_BLOCK_SIZE), 32);
+        assert_eq!(max_pending(8, ZSTD_FRAME_SIZE), 32);
+        assert_eq!(max_pending(8, ZSTD_LONG_FRAME_SIZE), 16);
+        assert_eq!(max_pending(1, MAX_PENDING_BYTES * 2), 1);
+        for threads in 1..=64 {
+            for block_size in [BGZF_BLOCK_SIZE, GZIP_MEMBER_SIZE, ZSTD_FRAME_SIZE, ZSTD_LONG_FRAME_SIZE] {
+                assert!(max_pending(threads, block_size) * block_size <= MAX_PENDING_BYTES);
+            }
+        }
+    }
+    #[test]
+    fn long_frames_are_buffered_as_data_arrives() {
+        let opts = CompressOptions { level: 4, zstd_level: 3, zstd_long: true };
+        let pool = Arc::new(CompressionPool::new(2, opts, Arc::new(Metrics::new(1, 1)), Arc::new(Gate::new(2))));
+        let mut writer = BlockWriter::new(Vec::new(), pool, BlockFormat::Zstd);
+        assert_eq!(writer.buf.capacity(), 0);
+        let line = b"@r1\nACGTTGCAACGTTGCAACGT\n+\nIIIIIIIIIIIIIIIIIIII\n";
+        writer.write_all(line).unwrap();
+        assert!(writer.buf.capacity() < 1024);
+        let mut expected = line.to_vec();
+        for i in 0..(ZSTD_LONG_FRAME_SIZE + (1 << 20)) / line.len() {
+            let record = format!("@r{i}\nACGTTGCAACGTTGCAACGT\n+\nIIIIIIIIIIIIIIIIIIII\n");
+            writer.write_all(record.as_bytes()).unwrap();
+            expected.extend_from_slice(record.as_bytes());
+            assert!(writer.buf.capacity() <= ZSTD_LONG_FRAME_SIZE);
+        }
+        let compressed = writer.finish().unwrap();
+        assert_eq!(zstd::decode_all(compressed.as_slice()).unwrap(), expected);
     }
 }
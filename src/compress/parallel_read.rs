@@ -0,0 +1,186 @@
+use crossbeam::channel::{bounded, Receiver, Sender};
+use flate2::read::MultiGzDecoder;
+use std::io::{self, BufRead, Read};
+use std::thread;
+
+type Chunk = io::Result<Vec<u8>>;
+
+/// Decoded bytes handed from the decoder thread(s) to the reader per message.
+const CHUNK_SIZE: usize = 1 << 20;
+/// BGZF blocks decoded together in one task (~1 MiB of output).
+const BLOCKS_PER_TASK: usize = 16;
+/// Chunks decoded ahead of the reader.
+const READ_AHEAD: usize = 16;
+
+/// Gzip header fields needed to walk BGZF blocks without inflating them.
+const GZIP_FIXED_HEADER: usize = 12;
+
+/// Run `decoder` on its own thread, `READ_AHEAD` chunks ahead of the consumer.
+pub fn pipelined(mut decoder: Box<dyn BufRead + Send>) -> ChunkReader {
+    let (tx, rx) = bounded::<Chunk>(READ_AHEAD);
+    thread::spawn(move || loop {
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        let mut filled = 0;
+        let res = loop {
+            match decoder.read(&mut buf[filled..]) {
+                Ok(0) => break Ok(()),
+                Ok(n) => {
+                    filled += n;
+                    if filled == buf.len() { break Ok(()); }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => break Err(e),
+            }
+        };
+        buf.truncate(filled);
+        let eof = buf.is_empty();
+        let msg = res.map(|_| buf);
+        let failed = msg.is_err();
+        if (!eof || failed) && tx.send(msg).is_err() { return; }
+        if eof || failed { return; }
+    });
+    ChunkReader { source: Source::Direct(rx), buf: Vec::new(), pos: 0, done: false }
+}
+
+/// Decode a BGZF stream block-parallel on `threads` threads; one extra thread splits the raw blocks.
+pub fn bgzf<R: Read + Send + 'static>(mut raw: R, threads: usize) -> ChunkReader {
+    let threads = threads.max(1);
+    let (task_tx, task_rx) = bounded::<(Vec<u8>, Sender<Chunk>)>(threads * 2);
+    let (order_tx, order_rx) = bounded::<Receiver<Chunk>>(threads * 4);
+
+    for _ in 0..threads {
+        let rx = task_rx.clone();
+        thread::spawn(move || {
+            while let Ok((data, reply)) = rx.recv() {
+                // every BGZF block is a complete gzip member
+                let mut out = Vec::with_capacity(data.len() * 4);
+                let res = MultiGzDecoder::new(&data[..]).read_to_end(&mut out).map(|_| out);
+                let _ = reply.send(res);
+            }
+        });
+    }
+
+    thread::spawn(move || loop {
+        let mut batch = Vec::new();
+        let mut res = Ok(());
+        for _ in 0..BLOCKS_PER_TASK {
+            match read_block(&mut raw, &mut batch) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => { res = Err(e); break; }
+            }
+        }
+        let (reply, result) = bounded(1);
+        let last = batch.is_empty() || res.is_err();
+        match res {
+            Ok(()) if batch.is_empty() => return,
+            Ok(()) => { let _ = task_tx.send((batch, reply)); }
+            Err(e) => { let _ = reply.send(Err(e)); }
+        }
+        if order_tx.send(result).is_err() || last { return; }
+    });
+
+    ChunkReader { source: Source::Ordered(order_rx), buf: Vec::new(), pos: 0, done: false }
+}
+
+/// Append the next raw BGZF block to `out`; `false` at a clean end of stream.
+fn read_block<R: Read>(raw: &mut R, out: &mut Vec<u8>) -> io::Result<bool> {
+    let mut header = [0u8; GZIP_FIXED_HEADER];
+    let mut got = 0;
+    while got < header.len() {
+        match raw.read(&mut header[got..]) {
+            Ok(0) if got == 0 => return Ok(false),
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated BGZF block header")),
+            Ok(n) => got += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    if header[0..2] != [0x1f, 0x8b] || header[3] & 0x04 == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a BGZF block"));
+    }
+    let xlen = u16::from_le_bytes([header[10], header[11]]) as usize;
+    let truncated = |e: io::Error| if e.kind() == io::ErrorKind::UnexpectedEof {
+        io::Error::new(io::ErrorKind::UnexpectedEof, "truncated BGZF block")
+    } else {
+        e
+    };
+    let mut extra = vec![0u8; xlen];
+    raw.read_exact(&mut extra).map_err(truncated)?;
+    let bsize = bgzf_block_size(&extra)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "BGZF block without BC field"))?;
+    let rest = bsize
+        .checked_sub(GZIP_FIXED_HEADER + xlen)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid BGZF block size"))?;
+    out.extend_from_slice(&header);
+    out.extend_from_slice(&extra);
+    let start = out.len();
+    out.resize(start + rest, 0);
+    raw.read_exact(&mut out[start..]).map_err(truncated)?;
+    Ok(true)
+}
+
+/// Total block size from the `BC` subfield of a gzip extra field.
+pub fn bgzf_block_size(extra: &[u8]) -> Option<usize> {
+    let mut i = 0;
+    while i + 4 <= extra.len() {
+        let slen = u16::from_le_bytes([extra[i + 2], extra[i + 3]]) as usize;
+        if extra[i] == b'B' && extra[i + 1] == b'C' && slen == 2 && i + 6 <= extra.len() {
+            return Some(u16::from_le_bytes([extra[i + 4], extra[i + 5]]) as usize + 1);
+        }
+        i += 4 + slen;
+    }
+    None
+}
+
+enum Source {
+    Direct(Receiver<Chunk>),
+    Ordered(Receiver<Receiver<Chunk>>),
+}
+
+/// `Read` side of the decoder threads: yields decoded chunks in stream order.
+pub struct ChunkReader {
+    source: Source,
+    buf: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl ChunkReader {
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let next = match &self.source {
+            Source::Direct(rx) => rx.recv().ok(),
+            Source::Ordered(rx) => rx.recv().ok().map(|r| {
+                r.recv().unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::BrokenPipe, "decompression thread exited")))
+            }),
+        };
+        match next {
+            Some(chunk) => { self.buf = chunk?; self.pos = 0; }
+            None => self.done = true,
+        }
+        Ok(())
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let avail = self.fill_buf()?;
+        let n = avail.len().min(out.len());
+        out[..n].copy_from_slice(&avail[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for ChunkReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.pos >= self.buf.len() && !self.done {
+            self.next_chunk()?;
+        }
+        Ok(&self.buf[self.pos.min(self.buf.len())..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt;
+    }
+}
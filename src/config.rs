@@ -1,13 +1,15 @@
 use clap::{Parser, ArgAction};
 use serde::{Deserialize, Serialize};
+use std::io;
 
 #[derive(Parser, Debug, Clone, Serialize, Deserialize)]
 #[command(name = "fastp-rs", version = "0.1.0", about = "FASTQ preprocessor (Rust)")]
 pub struct Cli {
-    #[arg(short='i', long="in1")]
-    pub in1: Option<String>,
-    #[arg(short='I', long="in2")]
-    pub in2: Option<String>,
+    // several inputs (space/comma separated or glob patterns) are read one after another
+    #[arg(short='i', long="in1", num_args=1.., value_delimiter=',')]
+    pub in1: Vec<String>,
+    #[arg(short='I', long="in2", num_args=1.., value_delimiter=',')]
+    pub in2: Vec<String>,
     #[arg(short='o', long="out1")]
     pub out1: Option<String>,
     #[arg(short='O', long="out2")]
@@ -16,6 +18,9 @@ pub struct Cli {
     pub stdin: bool,
     #[arg(long="stdout", action=ArgAction::SetTrue)]
     pub stdout: bool,
+    // phred quality given to FASTA input bases
+    #[arg(long="fasta_default_qual", default_value_t=crate::fastq::DEFAULT_FASTA_QUAL)]
+    pub fasta_default_qual: u8,
     
     // Trimming Options
     #[arg(short='f', long="trim_front1", default_value_t=0)]
@@ -44,7 +49,13 @@ pub struct Cli {
     #[arg(short='n', long="n_base_limit", default_value_t=5)]
     pub n_base_limit: usize,
     
-    // Reporting
+    // Output quality binning: `illumina8` or a table like `0-19:10,20-:30` (applied after filtering)
+    #[arg(long="quality_bins")]
+    pub quality_bins: Option<String>,
+
+    // Reporting; the duplication rate keeps a 128 MiB Bloom filter of raw sequences
+    #[arg(long="dont_eval_duplication", action=ArgAction::SetTrue)]
+    pub dont_eval_duplication: bool,
     #[arg(short='j', long="json", default_value = "fastp.json")]
     pub json: String,
     #[arg(long="html", default_value = "fastp.html")]
@@ -75,6 +86,40 @@ pub struct Cli {
     pub cut_right_window_size: usize,
     #[arg(long="cut_right_mean_quality", default_value_t=20)]
     pub cut_right_mean_quality: u8,
+    // R2 windows and thresholds; unset ones follow the R1 values above
+    #[arg(long="cut_front_window_size_r2")]
+    pub cut_front_window_size_r2: Option<usize>,
+    #[arg(long="cut_front_mean_quality_r2")]
+    pub cut_front_mean_quality_r2: Option<u8>,
+    #[arg(long="cut_tail_window_size_r2")]
+    pub cut_tail_window_size_r2: Option<usize>,
+    #[arg(long="cut_tail_mean_quality_r2")]
+    pub cut_tail_mean_quality_r2: Option<u8>,
+    #[arg(long="cut_right_window_size_r2")]
+    pub cut_right_window_size_r2: Option<usize>,
+    #[arg(long="cut_right_mean_quality_r2")]
+    pub cut_right_mean_quality_r2: Option<u8>,
+    // Mott/BWA running-sum quality trimming of both ends
+    #[arg(long="cut_mott", action=ArgAction::SetTrue)]
+    pub cut_mott: bool,
+    #[arg(long="cut_mott_quality", default_value_t=20)]
+    pub cut_mott_quality: u8,
+    #[arg(long="cut_mott_quality_r2")]
+    pub cut_mott_quality_r2: Option<u8>,
+
+    // Order of the trimming stages (comma-separated; stages left out are skipped)
+    #[arg(long="trim_order", default_value=crate::filter::DEFAULT_TRIM_ORDER)]
+    pub trim_order: String,
+
+    // Amplicon primer trimming (primer FASTA, or primer BED + reference FASTA)
+    #[arg(long="primers")]
+    pub primers: Option<String>,
+    #[arg(long="primer_reference")]
+    pub primer_reference: Option<String>,
+    #[arg(long="primer_mismatches", default_value_t=2)]
+    pub primer_mismatches: usize,
+    #[arg(long="primer_discard_unmatched", action=ArgAction::SetTrue)]
+    pub primer_discard_unmatched: bool,
 
     // Adapter Trimming
     #[arg(short='A', long="disable_adapter_trimming", action=ArgAction::SetTrue)]
@@ -83,6 +128,15 @@ pub struct Cli {
     pub adapter_sequence: Option<String>,
     #[arg(long="adapter_sequence_r2")]
     pub adapter_sequence_r2: Option<String>,
+    // one mismatch allowed per this many adapter bases compared (one fewer with an indel)
+    #[arg(long="adapter_bases_per_edit", default_value_t=crate::filter::DEFAULT_BASES_PER_EDIT)]
+    pub adapter_bases_per_edit: usize,
+    // adapter dimers: at most this many bases left once the adapter is cut; optionally written
+    // (untrimmed, mates interleaved) to a file of their own instead of the output
+    #[arg(long="adapter_dimer_max_insert", default_value_t=crate::filter::DEFAULT_DIMER_MAX_INSERT)]
+    pub adapter_dimer_max_insert: usize,
+    #[arg(long="adapter_dimer_out")]
+    pub adapter_dimer_out: Option<String>,
     
     // PolyG Trimming
     #[arg(long="trim_poly_g", action=ArgAction::SetTrue)]
@@ -98,6 +152,34 @@ pub struct Cli {
     #[arg(long="poly_x_min_len", default_value_t=10)]
     pub poly_x_min_len: usize,
 
+    // Masking: bases below this phred become N (0 = off), and the last bases before an adapter cut
+    #[arg(long="mask_low_quality", default_value_t=0)]
+    pub mask_low_quality: u8,
+    #[arg(long="mask_adapter_window", default_value_t=0)]
+    pub mask_adapter_window: usize,
+
+    // Read limit and seeded subsampling (pairs are kept or dropped together)
+    #[arg(long="reads_to_process", default_value_t=0)]
+    pub reads_to_process: u64,
+    #[arg(long="subsample_fraction", default_value_t=0.0)]
+    pub subsample_fraction: f64,
+    #[arg(long="subsample_count", default_value_t=0)]
+    pub subsample_count: usize,
+    #[arg(long="seed", default_value_t=11)]
+    pub seed: u64,
+
+    // Long-read mode (Nanopore/PacBio): single-end, packs by bases, no PE logic
+    #[arg(long="long_read", action=ArgAction::SetTrue)]
+    pub long_read: bool,
+    #[arg(long="trim_head_quality", default_value_t=0)]
+    pub trim_head_quality: u8,
+    #[arg(long="trim_tail_quality", default_value_t=0)]
+    pub trim_tail_quality: u8,
+    #[arg(long="long_trim_window", default_value_t=20)]
+    pub long_trim_window: usize,
+    #[arg(short='m', long="mean_qual", default_value_t=0)]
+    pub mean_qual: u8,
+
     // Overlap analysis and correction (PE)
     #[arg(short='c', long="correction", action=ArgAction::SetTrue)]
     pub correction: bool,
@@ -107,22 +189,130 @@ pub struct Cli {
     pub overlap_diff_limit: usize,
     #[arg(long="overlap_diff_percent_limit", default_value_t=20)]
     pub overlap_diff_percent_limit: u8,
+    // pick the overlap offset with the fewest mismatches instead of upstream's first hit
+    #[arg(long="overlap_best_match", action=ArgAction::SetTrue)]
+    pub overlap_best_match: bool,
+    // merged quality cap of agreeing overlap bases with -c (0 = keep qualities)
+    #[arg(long="correction_max_qual", default_value_t=crate::filter::DEFAULT_MAX_MERGED_QUAL)]
+    pub correction_max_qual: u8,
 
     // Threading
     #[arg(short='w', long="thread", default_value_t=2)]
     pub thread: usize,
 
     // Performance tuning
-    #[arg(long="pack_size", default_value_t=1000)]
+    // print a progress line to stderr every this many seconds (0 = off)
+    #[arg(long="progress_interval", default_value_t=0)]
+    pub progress_interval: u64,
+    // reads, or bases, per pack (0 = sized by bases, tuned while running)
+    #[arg(long="pack_size", default_value_t=0)]
     pub pack_size: usize,
+    #[arg(long="pack_bases", default_value_t=0)]
+    pub pack_bases: usize,
+    // fixed thread split: -w workers plus -w compression threads, 1000-read packs
+    #[arg(long="disable_autotune", action=ArgAction::SetTrue)]
+    pub disable_autotune: bool,
     #[arg(long="queue_depth", default_value_t=0)]
     pub queue_depth: usize,
+    // BGZF input decompression threads (0 = derive from -w)
+    #[arg(long="decompress_threads", default_value_t=0)]
+    pub decompress_threads: usize,
     #[arg(short='z', long="compression", default_value_t=4)]
     pub compression: u32,
+    // BGZF block-compressed output (.gz outputs, .bgz always)
+    #[arg(long="bgzf", action=ArgAction::SetTrue)]
+    pub bgzf: bool,
+    #[arg(long="bgzf_index", action=ArgAction::SetTrue)]
+    pub bgzf_index: bool,
+    // zstd output (.zst)
+    #[arg(long="zstd_level", default_value_t=3)]
+    pub zstd_level: i32,
+    #[arg(long="zstd_long", action=ArgAction::SetTrue)]
+    pub zstd_long: bool,
 
-    // External compressor (pigz)
-    #[arg(long="pigz", action=ArgAction::SetTrue)]
+    // External compressors: pigz for .gz, bgzip for BGZF, zstd for .zst
+    #[arg(long="pigz", alias="external_compressor", action=ArgAction::SetTrue)]
     pub pigz: bool,
     #[arg(long="pigz_threads", default_value_t=0)]
     pub pigz_threads: usize,
 }
+
+impl Cli {
+    /// Expand glob patterns in `-i`/`-I` to the matching files, sorted by name.
+    pub fn expand_inputs(&mut self) -> io::Result<()> {
+        self.in1 = expand_globs(&self.in1)?;
+        self.in2 = expand_globs(&self.in2)?;
+        if !self.in2.is_empty() && self.in1.len() != self.in2.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} read1 inputs but {} read2 inputs", self.in1.len(), self.in2.len())));
+        }
+        Ok(())
+    }
+
+    /// Turn off the filters and cutters that depend on base qualities (for FASTA input).
+    /// Returns the ones the user asked for, so they can be warned about.
+    pub fn disable_quality_filters(&mut self) -> Vec<&'static str> {
+        let mut ignored = Vec::new();
+        if self.qualified_quality_phred != 15 || self.unqualified_percent_limit != 40 { ignored.push("--qualified_quality_phred/--unqualified_percent_limit"); }
+        if self.average_qual > 0 { ignored.push("--average_qual"); }
+        if self.cut_front { ignored.push("--cut_front"); }
+        if self.cut_tail { ignored.push("--cut_tail"); }
+        if self.cut_right { ignored.push("--cut_right"); }
+        if self.cut_mott { ignored.push("--cut_mott"); }
+        if self.mask_low_quality > 0 { ignored.push("--mask_low_quality"); }
+        if self.mean_qual > 0 { ignored.push("--mean_qual"); }
+        if self.trim_head_quality > 0 { ignored.push("--trim_head_quality"); }
+        if self.trim_tail_quality > 0 { ignored.push("--trim_tail_quality"); }
+        self.average_qual = 0;
+        self.cut_front = false;
+        self.cut_tail = false;
+        self.cut_right = false;
+        self.cut_mott = false;
+        self.mask_low_quality = 0;
+        self.mean_qual = 0;
+        self.trim_head_quality = 0;
+        self.trim_tail_quality = 0;
+        // no base is below phred 0, so the unqualified-percent filter never fires
+        self.qualified_quality_phred = 0;
+        ignored
+    }
+}
+
+fn expand_globs(patterns: &[String]) -> io::Result<Vec<String>> {
+    let mut paths = Vec::new();
+    for pattern in patterns {
+        if !pattern.contains(['*', '?', '[']) {
+            paths.push(pattern.clone());
+            continue;
+        }
+        let matches = glob::glob(pattern).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("bad input pattern {}: {}", pattern, e)))?;
+        let before = paths.len();
+        for m in matches {
+            let path = m.map_err(io::Error::from)?;
+            paths.push(path.to_string_lossy().into_owned());
+        }
+        if paths.len() == before {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("no input file matches {}", pattern)));
+        }
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn fasta_input_disables_every_quality_option() {
+        let mut cli = Cli::parse_from([
+            "fastp_rs", "-e", "20", "--cut_front", "--cut_tail", "--cut_right", "--cut_mott", "--mask_low_quality", "15",
+            "--mean_qual", "20", "--trim_head_quality", "20", "--trim_tail_quality", "20", "-q", "20",
+        ]);
+        let ignored = cli.disable_quality_filters();
+        assert_eq!(ignored.len(), 10);
+        assert!(ignored.contains(&"--mean_qual") && ignored.contains(&"--trim_head_quality") && ignored.contains(&"--trim_tail_quality"));
+        assert_eq!((cli.average_qual, cli.mean_qual, cli.trim_head_quality, cli.trim_tail_quality, cli.mask_low_quality), (0, 0, 0, 0, 0));
+        assert!(!(cli.cut_front || cli.cut_tail || cli.cut_right || cli.cut_mott));
+        assert!(Cli::parse_from(["fastp_rs"]).disable_quality_filters().is_empty());
+    }
+}
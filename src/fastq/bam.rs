@@ -0,0 +1,431 @@
+use std::io::{self, BufRead, Read, Write};
+use super::FastqRecord;
+
+pub const BAM_MAGIC: &[u8; 4] = b"BAM\x01";
+
+pub const FLAG_PAIRED: u16 = 0x1;
+pub const FLAG_UNMAPPED: u16 = 0x4;
+pub const FLAG_MATE_UNMAPPED: u16 = 0x8;
+pub const FLAG_REVERSE: u16 = 0x10;
+pub const FLAG_READ1: u16 = 0x40;
+pub const FLAG_READ2: u16 = 0x80;
+pub const FLAG_SECONDARY: u16 = 0x100;
+pub const FLAG_SUPPLEMENTARY: u16 = 0x800;
+
+/// Fixed part of a BAM alignment record after `block_size`.
+const FIXED_LEN: usize = 32;
+/// reg2bin(-1, 0): the bin of an unplaced read.
+const UNMAPPED_BIN: u16 = 4680;
+/// Quality used when a record stores no qualities (0xff), Phred 30.
+pub const MISSING_QUAL: u8 = b'?';
+
+const SEQ_CODES: &[u8; 16] = b"=ACMGRSVTWYHKDBN";
+
+/// Decodes an (unaligned) BAM stream into FASTQ records.
+/// Aux tags are kept in SAM text form in the read comment, as `samtools fastq -T` does,
+/// so that `BamWriter` can restore them.
+pub struct BamReader {
+    reader: Box<dyn BufRead + Send>,
+    header: String,
+    peeked: Option<(FastqRecord, u16)>,
+}
+
+impl BamReader {
+    /// `reader` is the decompressed stream, positioned at the BAM magic.
+    pub fn new(mut reader: Box<dyn BufRead + Send>) -> io::Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != BAM_MAGIC {
+            return Err(invalid("not a BAM file"));
+        }
+        let l_text = read_u32(&mut reader)? as usize;
+        let mut text = vec![0u8; l_text];
+        reader.read_exact(&mut text)?;
+        let n_ref = read_u32(&mut reader)?;
+        for _ in 0..n_ref {
+            let l_name = read_u32(&mut reader)? as usize;
+            // name + l_ref
+            io::copy(&mut (&mut reader).take(l_name as u64 + 4), &mut io::sink())?;
+        }
+        let end = text.iter().position(|&b| b == 0).unwrap_or(text.len());
+        let header = String::from_utf8_lossy(&text[..end]).into_owned();
+        let mut r = Self { reader, header, peeked: None };
+        r.peeked = r.read_primary()?;
+        Ok(r)
+    }
+
+    pub fn header(&self) -> &str {
+        &self.header
+    }
+
+    /// Whether the records are mate pairs (first record has the paired flag).
+    pub fn is_paired(&self) -> bool {
+        matches!(&self.peeked, Some((_, flag)) if flag & FLAG_PAIRED != 0)
+    }
+
+    /// Next primary record and its flag.
+    pub fn next_record(&mut self) -> io::Result<Option<(FastqRecord, u16)>> {
+        match self.peeked.take() {
+            Some(r) => Ok(Some(r)),
+            None => self.read_primary(),
+        }
+    }
+
+    fn read_primary(&mut self) -> io::Result<Option<(FastqRecord, u16)>> {
+        loop {
+            match self.read_raw()? {
+                Some((_, flag)) if flag & (FLAG_SECONDARY | FLAG_SUPPLEMENTARY) != 0 => continue,
+                other => return Ok(other),
+            }
+        }
+    }
+
+    fn read_raw(&mut self) -> io::Result<Option<(FastqRecord, u16)>> {
+        let mut size = [0u8; 4];
+        if !read_exact_or_eof(&mut self.reader, &mut size)? {
+            return Ok(None);
+        }
+        let block_size = u32::from_le_bytes(size) as usize;
+        if block_size < FIXED_LEN {
+            return Err(invalid("truncated BAM record"));
+        }
+        let mut block = vec![0u8; block_size];
+        self.reader.read_exact(&mut block)?;
+        decode_record(&block).map(Some)
+    }
+}
+
+fn decode_record(b: &[u8]) -> io::Result<(FastqRecord, u16)> {
+    let l_read_name = b[8] as usize;
+    let n_cigar_op = u16::from_le_bytes([b[12], b[13]]) as usize;
+    let flag = u16::from_le_bytes([b[14], b[15]]);
+    let l_seq = u32::from_le_bytes([b[16], b[17], b[18], b[19]]) as usize;
+
+    let name_start = FIXED_LEN;
+    let seq_start = name_start + l_read_name + n_cigar_op * 4;
+    let qual_start = seq_start + l_seq.div_ceil(2);
+    let tags_start = qual_start + l_seq;
+    if tags_start > b.len() || l_read_name == 0 {
+        return Err(invalid("truncated BAM record"));
+    }
+
+    let name = String::from_utf8_lossy(&b[name_start..name_start + l_read_name - 1]);
+    let mut seq: Vec<u8> = (0..l_seq)
+        .map(|i| {
+            let byte = b[seq_start + i / 2];
+            SEQ_CODES[(if i % 2 == 0 { byte >> 4 } else { byte & 0x0f }) as usize]
+        })
+        .collect();
+    let raw_qual = &b[qual_start..tags_start];
+    let mut qual: Vec<u8> = if raw_qual.first() == Some(&0xff) {
+        vec![MISSING_QUAL; l_seq]
+    } else {
+        raw_qual.iter().map(|&q| q.saturating_add(33).min(126)).collect()
+    };
+    restore_orientation(&mut seq, &mut qual, flag);
+
+    let mut header = format!("@{}", name);
+    for tag in decode_tags(&b[tags_start..])? {
+        header.push('\t');
+        header.push_str(&tag);
+    }
+    let rec = FastqRecord {
+        name: header,
+        seq: String::from_utf8(seq).unwrap_or_default(),
+        plus: "+".to_string(),
+        qual: String::from_utf8(qual).unwrap_or_default(),
+    };
+    Ok((rec, flag))
+}
+
+/// Reverse-complement a record stored on the reverse strand back to its sequencing orientation.
+pub fn restore_orientation(seq: &mut [u8], qual: &mut [u8], flag: u16) {
+    if flag & FLAG_REVERSE != 0 {
+        seq.reverse();
+        for c in seq.iter_mut() { *c = complement(*c); }
+        qual.reverse();
+    }
+}
+
+/// Binary aux fields to SAM text (`TG:T:value`).
+fn decode_tags(mut b: &[u8]) -> io::Result<Vec<String>> {
+    let mut tags = Vec::new();
+    while b.len() >= 3 {
+        let tag = String::from_utf8_lossy(&b[0..2]).into_owned();
+        let ty = b[2];
+        b = &b[3..];
+        let (text, used) = match ty {
+            b'A' => (format!("A:{}", *b.first().ok_or_else(bad_tag)? as char), 1),
+            b'c' | b'C' | b's' | b'S' | b'i' | b'I' => {
+                let (v, n) = read_int(ty, b)?;
+                (format!("i:{}", v), n)
+            }
+            b'f' => (format!("f:{}", f32::from_le_bytes(take4(b)?)), 4),
+            b'Z' | b'H' => {
+                let end = b.iter().position(|&c| c == 0).ok_or_else(bad_tag)?;
+                (format!("{}:{}", ty as char, String::from_utf8_lossy(&b[..end])), end + 1)
+            }
+            b'B' => {
+                let sub = *b.first().ok_or_else(bad_tag)?;
+                let count = u32::from_le_bytes(take4(&b[1..])?) as usize;
+                let mut text = format!("B:{}", sub as char);
+                let mut off = 5;
+                for _ in 0..count {
+                    let rest = b.get(off..).ok_or_else(bad_tag)?;
+                    if sub == b'f' {
+                        text.push_str(&format!(",{}", f32::from_le_bytes(take4(rest)?)));
+                        off += 4;
+                    } else {
+                        let (v, n) = read_int(sub, rest)?;
+                        text.push_str(&format!(",{}", v));
+                        off += n;
+                    }
+                }
+                (text, off)
+            }
+            _ => return Err(bad_tag()),
+        };
+        if used > b.len() {
+            return Err(bad_tag());
+        }
+        tags.push(format!("{}:{}", tag, text));
+        b = &b[used..];
+    }
+    Ok(tags)
+}
+
+fn read_int(ty: u8, b: &[u8]) -> io::Result<(i64, usize)> {
+    let need = match ty { b'c' | b'C' => 1, b's' | b'S' => 2, _ => 4 };
+    if b.len() < need {
+        return Err(bad_tag());
+    }
+    let v = match ty {
+        b'c' => b[0] as i8 as i64,
+        b'C' => b[0] as i64,
+        b's' => i16::from_le_bytes([b[0], b[1]]) as i64,
+        b'S' => u16::from_le_bytes([b[0], b[1]]) as i64,
+        b'i' => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as i64,
+        b'I' => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as i64,
+        _ => return Err(bad_tag()),
+    };
+    Ok((v, need))
+}
+
+fn take4(b: &[u8]) -> io::Result<[u8; 4]> {
+    b.get(..4).and_then(|s| s.try_into().ok()).ok_or_else(bad_tag)
+}
+
+/// Encodes FASTQ records as unaligned BAM records (into an already BGZF-compressing stream).
+pub struct BamWriter;
+
+impl BamWriter {
+    /// BAM header without reference sequences. `@SQ` lines are dropped since uBAM has no references.
+    pub fn write_header<W: Write>(w: &mut W, text: &str) -> io::Result<()> {
+        let mut text: String = text
+            .lines()
+            .filter(|l| !l.is_empty() && !l.starts_with("@SQ"))
+            .map(|l| format!("{}\n", l))
+            .collect();
+        if !text.starts_with("@HD") {
+            text.insert_str(0, "@HD\tVN:1.6\tSO:unsorted\n");
+        }
+        if !text.lines().any(|l| l.starts_with("@PG") && l.contains("\tID:fastp-rs")) {
+            text.push_str(&format!("@PG\tID:fastp-rs\tPN:fastp-rs\tVN:{}\n", env!("CARGO_PKG_VERSION")));
+        }
+        w.write_all(BAM_MAGIC)?;
+        w.write_all(&(text.len() as u32).to_le_bytes())?;
+        w.write_all(text.as_bytes())?;
+        w.write_all(&0u32.to_le_bytes())
+    }
+
+    /// One unmapped record; `flag` carries the pairing bits.
+    pub fn write_record<W: Write>(w: &mut W, rec: &FastqRecord, flag: u16) -> io::Result<()> {
+        let header = rec.name.strip_prefix('@').unwrap_or(&rec.name);
+        let (name, comment) = match header.find([' ', '\t']) {
+            Some(i) => (&header[..i], &header[i + 1..]),
+            None => (header, ""),
+        };
+        let name = name.strip_suffix("/1").or_else(|| name.strip_suffix("/2")).unwrap_or(name);
+        let name = if name.is_empty() { "*" } else { name };
+        // at most 254 bytes, cut on a character boundary
+        let end = (0..=name.len().min(254)).rev().find(|&i| name.is_char_boundary(i)).unwrap_or(0);
+        let name = &name[..end];
+        let seq = rec.seq.as_bytes();
+        let qual = rec.qual.as_bytes();
+
+        let mut tags = Vec::new();
+        for field in comment.split([' ', '\t']) {
+            encode_tag(field, &mut tags);
+        }
+
+        let l_seq = seq.len();
+        let block_size = FIXED_LEN + name.len() + 1 + l_seq.div_ceil(2) + l_seq + tags.len();
+        let mut b = Vec::with_capacity(block_size + 4);
+        b.extend_from_slice(&(block_size as u32).to_le_bytes());
+        b.extend_from_slice(&(-1i32).to_le_bytes()); // refID
+        b.extend_from_slice(&(-1i32).to_le_bytes()); // pos
+        b.push(name.len() as u8 + 1);
+        b.push(0); // mapq
+        b.extend_from_slice(&UNMAPPED_BIN.to_le_bytes());
+        b.extend_from_slice(&0u16.to_le_bytes()); // n_cigar_op
+        b.extend_from_slice(&flag.to_le_bytes());
+        b.extend_from_slice(&(l_seq as u32).to_le_bytes());
+        b.extend_from_slice(&(-1i32).to_le_bytes()); // next_refID
+        b.extend_from_slice(&(-1i32).to_le_bytes()); // next_pos
+        b.extend_from_slice(&0i32.to_le_bytes()); // tlen
+        b.extend_from_slice(name.as_bytes());
+        b.push(0);
+        for pair in seq.chunks(2) {
+            let hi = seq_code(pair[0]);
+            let lo = pair.get(1).map(|&c| seq_code(c)).unwrap_or(0);
+            b.push(hi << 4 | lo);
+        }
+        // qualities that do not cover the sequence are stored as missing (all 0xff)
+        if qual.len() == l_seq {
+            b.extend(qual.iter().map(|q| q.saturating_sub(33)));
+        } else {
+            b.resize(b.len() + l_seq, 0xff);
+        }
+        b.extend_from_slice(&tags);
+        w.write_all(&b)
+    }
+}
+
+/// SAM text aux field to binary; anything that is not `TG:T:value` (e.g. Illumina `1:N:0:1`) is skipped.
+fn encode_tag(field: &str, out: &mut Vec<u8>) {
+    let f = field.as_bytes();
+    if f.len() < 5 || f[2] != b':' || f[4] != b':' || !f[0].is_ascii_alphabetic() || !f[1].is_ascii_alphanumeric() {
+        return;
+    }
+    let value = &field[5..];
+    out.extend_from_slice(&f[0..2]);
+    match f[3] {
+        b'A' if value.len() == 1 => { out.push(b'A'); out.push(value.as_bytes()[0]); }
+        b'i' => match value.parse::<i64>() {
+            Ok(v) => push_int(v, out),
+            Err(_) => out.truncate(out.len() - 2),
+        },
+        b'f' => match value.parse::<f32>() {
+            Ok(v) => { out.push(b'f'); out.extend_from_slice(&v.to_le_bytes()); }
+            Err(_) => out.truncate(out.len() - 2),
+        },
+        b'Z' | b'H' => { out.push(f[3]); out.extend_from_slice(value.as_bytes()); out.push(0); }
+        b'B' => {
+            let mut parts = value.split(',');
+            let sub = parts.next().and_then(|s| s.bytes().next()).unwrap_or(b'i');
+            let values: Vec<&str> = parts.collect();
+            out.push(b'B');
+            out.push(sub);
+            out.extend_from_slice(&(values.len() as u32).to_le_bytes());
+            for v in values {
+                match sub {
+                    b'f' => out.extend_from_slice(&v.parse::<f32>().unwrap_or(0.0).to_le_bytes()),
+                    b'c' | b'C' => out.push(v.parse::<i64>().unwrap_or(0) as u8),
+                    b's' | b'S' => out.extend_from_slice(&(v.parse::<i64>().unwrap_or(0) as u16).to_le_bytes()),
+                    _ => out.extend_from_slice(&(v.parse::<i64>().unwrap_or(0) as u32).to_le_bytes()),
+                }
+            }
+        }
+        _ => out.truncate(out.len() - 2),
+    }
+}
+
+/// Smallest integer type holding `v`, as htslib does.
+fn push_int(v: i64, out: &mut Vec<u8>) {
+    if v >= 0 {
+        if v <= u8::MAX as i64 { out.push(b'C'); out.push(v as u8); }
+        else if v <= u16::MAX as i64 { out.push(b'S'); out.extend_from_slice(&(v as u16).to_le_bytes()); }
+        else { out.push(b'I'); out.extend_from_slice(&(v as u32).to_le_bytes()); }
+    } else if v >= i8::MIN as i64 { out.push(b'c'); out.push(v as i8 as u8); }
+    else if v >= i16::MIN as i64 { out.push(b's'); out.extend_from_slice(&(v as i16).to_le_bytes()); }
+    else { out.push(b'i'); out.extend_from_slice(&(v as i32).to_le_bytes()); }
+}
+
+fn seq_code(b: u8) -> u8 {
+    SEQ_CODES.iter().position(|&c| c == b.to_ascii_uppercase()).unwrap_or(15) as u8
+}
+
+fn complement(b: u8) -> u8 {
+    match b {
+        b'A' => b'T',
+        b'T' => b'A',
+        b'C' => b'G',
+        b'G' => b'C',
+        _ => b'N',
+    }
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut b = [0u8; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+/// `false` on a clean end of stream before the first byte.
+fn read_exact_or_eof<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    let mut got = 0;
+    while got < buf.len() {
+        match r.read(&mut buf[got..]) {
+            Ok(0) if got == 0 => return Ok(false),
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated BAM record")),
+            Ok(n) => got += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn bad_tag() -> io::Error {
+    invalid("malformed BAM aux field")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rec(name: &str, seq: &str, qual: &str) -> FastqRecord {
+        FastqRecord { name: name.to_string(), seq: seq.to_string(), plus: "+".to_string(), qual: qual.to_string() }
+    }
+
+    fn round_trip(records: &[(FastqRecord, u16)]) -> Vec<(FastqRecord, u16)> {
+        let mut bam = Vec::new();
+        BamWriter::write_header(&mut bam, "@RG\tID:lane1\n").unwrap();
+        for (r, flag) in records {
+            BamWriter::write_record(&mut bam, r, *flag).unwrap();
+        }
+        let mut reader = BamReader::new(Box::new(io::Cursor::new(bam))).unwrap();
+        assert!(reader.header().contains("@RG\tID:lane1"));
+        std::iter::from_fn(|| reader.next_record().unwrap()).collect()
+    }
+
+    #[test]
+    fn records_and_tags_round_trip() {
+        let flag = FLAG_PAIRED | FLAG_UNMAPPED | FLAG_MATE_UNMAPPED;
+        let out = round_trip(&[
+            (rec("@r1/1 RG:Z:lane1\tBC:Z:ACGT", "ACGTN", "IIII#"), flag | FLAG_READ1),
+            (rec("@r1/2 RG:Z:lane1", "TTGCA", "#IIII"), flag | FLAG_READ2),
+        ]);
+        assert_eq!(out[0].0.name, "@r1\tRG:Z:lane1\tBC:Z:ACGT");
+        assert_eq!((out[0].0.seq.as_str(), out[0].0.qual.as_str()), ("ACGTN", "IIII#"));
+        assert_eq!(out[1].0.name, "@r1\tRG:Z:lane1");
+        assert_eq!(out[1].1, flag | FLAG_READ2);
+    }
+
+    #[test]
+    fn long_non_ascii_names_are_cut_on_a_character_boundary() {
+        let name = format!("@{}", "é".repeat(200));
+        let out = round_trip(&[(rec(&name, "ACGT", "IIII"), FLAG_UNMAPPED)]);
+        assert_eq!(out[0].0.name, format!("@{}", "é".repeat(127)));
+    }
+
+    #[test]
+    fn qualities_not_covering_the_sequence_are_missing() {
+        let out = round_trip(&[(rec("@short", "ACGT", "II"), FLAG_UNMAPPED), (rec("@none", "ACGT", ""), FLAG_UNMAPPED)]);
+        assert!(out.iter().all(|(r, _)| r.qual == "????"));
+    }
+}
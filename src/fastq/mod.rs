@@ -1,8 +1,22 @@
-use std::io::{self, Read, Write, BufRead, BufReader};
+use std::collections::VecDeque;
+use std::io::{self, Write, BufRead, BufWriter};
 use std::fs::File;
+use std::sync::Arc;
 use flate2::write::GzEncoder;
 use flate2::Compression;
-use flate2::read::MultiGzDecoder;
+use crate::compress::{self, BlockFormat, BlockWriter, CompressOptions, CompressionPool, ExternalCompressor, InputFormat};
+
+mod bam;
+mod quality_bins;
+mod sam;
+
+pub use self::quality_bins::QualityBins;
+
+/// Default phred quality of FASTA bases (`--fasta_default_qual`).
+pub const DEFAULT_FASTA_QUAL: u8 = 30;
+
+use self::bam::{BamReader, BamWriter, BAM_MAGIC};
+use self::sam::SamReader;
 
 #[derive(Debug, Clone)]
 pub struct FastqRecord {
@@ -13,88 +27,432 @@ pub struct FastqRecord {
 }
 
 impl FastqRecord {
+    #[allow(dead_code)]
     pub fn new(name: String, seq: String, plus: String, qual: String) -> Self {
         Self { name, seq, plus, qual }
     }
 }
 
+enum Source {
+    Fastq(Box<dyn BufRead + Send>),
+    Fasta(Box<dyn BufRead + Send>),
+    Bam(BamReader),
+    Sam(SamReader),
+}
+
 pub struct Reader {
-    reader: Box<dyn BufRead>,
+    source: Source,
+    /// quality character given to every base of a FASTA record
+    fasta_qual: char,
+    /// FASTQ sequence and quality may be wrapped over several lines (long-read mode)
+    multi_line: bool,
+    /// inputs chained after the current one
+    pending: VecDeque<String>,
+    threads: usize,
+    /// the first input is BGZF, inflated on `threads` threads
+    bgzf: bool,
+    /// reads taken from each input opened so far
+    counts: Vec<(String, u64)>,
 }
 
 impl Reader {
-    pub fn new(path: Option<&str>, stdin: bool) -> io::Result<Self> {
-        let reader: Box<dyn BufRead> = if stdin || path == Some("/dev/stdin") || path.is_none() {
-            Box::new(BufReader::new(io::stdin()))
+    /// `threads` is the number of decompression threads used for BGZF input.
+    /// Unaligned BAM is recognised by its magic and SAM text by its header (or record) line,
+    /// both decoded into FASTQ records;
+    /// FASTA (first byte `>`) gets a synthesized quality, see `with_fasta_quality`.
+    pub fn new(path: Option<&str>, stdin: bool, threads: usize) -> io::Result<Self> {
+        let ((source, format), name) = match path {
+            Some(p) if !stdin && p != "/dev/stdin" => (Self::open(Some(p), threads)?, p.to_string()),
+            _ => (Self::open(None, threads)?, "stdin".to_string()),
+        };
+        Ok(Self {
+            source,
+            fasta_qual: (DEFAULT_FASTA_QUAL + 33) as char,
+            multi_line: false,
+            pending: VecDeque::new(),
+            threads,
+            bgzf: format == InputFormat::Bgzf,
+            counts: vec![(name, 0)],
+        })
+    }
+
+    /// Read `paths` one after another as a single input (e.g. lanes L001..L004).
+    pub fn chain(paths: &[String], stdin: bool, threads: usize) -> io::Result<Self> {
+        let mut reader = Self::new(paths.first().map(String::as_str), stdin, threads)?;
+        reader.pending = paths.iter().skip(1).cloned().collect();
+        Ok(reader)
+    }
+
+    fn open(path: Option<&str>, threads: usize) -> io::Result<(Source, InputFormat)> {
+        // compression is detected from magic bytes, so stdin and misnamed files work too
+        let (mut reader, format) = match path {
+            Some(p) => compress::open_decoder(File::open(p)?, threads)?,
+            None => compress::open_decoder(io::stdin(), threads)?,
+        };
+        let magic = reader.fill_buf()?;
+        let source = if magic.starts_with(BAM_MAGIC) {
+            Source::Bam(BamReader::new(reader)?)
+        } else if magic.starts_with(b"CRAM") {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "CRAM input is not supported, convert it with `samtools view -b`"));
+        } else if sam::is_sam(magic) {
+            Source::Sam(SamReader::new(reader)?)
+        } else if magic.starts_with(b">") {
+            Source::Fasta(reader)
         } else {
-            let p = path.unwrap();
-            let f = File::open(p)?;
-            if p.ends_with(".gz") {
-                Box::new(BufReader::new(MultiGzDecoder::new(f)))
-            } else {
-                Box::new(BufReader::new(f))
-            }
+            Source::Fastq(reader)
         };
-        Ok(Self { reader })
+        Ok((source, format))
+    }
+
+    /// Threads inflating the first input block-parallel (BGZF), 0 for other inputs.
+    pub fn decode_threads(&self) -> usize {
+        if self.bgzf { self.threads } else { 0 }
+    }
+
+    /// Phred quality synthesized for each base of FASTA input.
+    pub fn with_fasta_quality(mut self, phred: u8) -> Self {
+        self.fasta_qual = (phred.min(93) + 33) as char;
+        self
+    }
+
+    /// Accept FASTQ records wrapped over several lines, as long-read tools write them.
+    pub fn with_multi_line(mut self, multi_line: bool) -> Self {
+        self.multi_line = multi_line;
+        self
+    }
+
+    /// Input has no base qualities of its own.
+    pub fn is_fasta(&self) -> bool {
+        matches!(self.source, Source::Fasta(_))
+    }
+
+    /// Paired uBAM or SAM: both mates come from this one input, read1 then read2.
+    pub fn is_interleaved(&self) -> bool {
+        match &self.source {
+            Source::Bam(b) => b.is_paired(),
+            Source::Sam(s) => s.is_paired(),
+            _ => false,
+        }
+    }
+
+    /// Header text of a BAM or SAM input.
+    pub fn bam_header(&self) -> Option<&str> {
+        match &self.source {
+            Source::Bam(b) => Some(b.header()),
+            Source::Sam(s) => Some(s.header()),
+            _ => None,
+        }
+    }
+
+    /// Index of the input file the last record came from.
+    pub fn file_index(&self) -> usize {
+        self.counts.len() - 1
+    }
+
+    /// Reads taken from each input file, in input order.
+    pub fn file_counts(&self) -> &[(String, u64)] {
+        &self.counts
     }
 
     pub fn next_record(&mut self) -> io::Result<Option<FastqRecord>> {
-        // block buffer to reduce allocations
+        Ok(self.next_raw()?.map(|(rec, _)| rec))
+    }
+
+    /// Next record with its SAM flag (0 for FASTQ/FASTA), moving on to the next chained input at EOF.
+    fn next_raw(&mut self) -> io::Result<Option<(FastqRecord, u16)>> {
+        loop {
+            let rec = match &mut self.source {
+                Source::Fastq(r) => Self::next_fastq(r, self.multi_line)?.map(|rec| (rec, 0)),
+                Source::Fasta(r) => Self::next_fasta(r, self.fasta_qual)?.map(|rec| (rec, 0)),
+                Source::Bam(b) => b.next_record()?,
+                Source::Sam(s) => s.next_record()?,
+            };
+            if rec.is_some() {
+                if let Some(c) = self.counts.last_mut() { c.1 += 1; }
+                return Ok(rec);
+            }
+            let Some(path) = self.pending.pop_front() else { return Ok(None) };
+            self.source = Self::open(Some(&path), self.threads)?.0;
+            self.counts.push((path, 0));
+        }
+    }
+
+    /// Next mate pair of an interleaved input.
+    pub fn next_pair(&mut self) -> io::Result<(Option<FastqRecord>, Option<FastqRecord>)> {
+        let first = self.next_raw()?;
+        let second = self.next_raw()?;
+        match (first, second) {
+            (Some((r1, f1)), Some((r2, f2))) => {
+                if (f1 | f2) & bam::FLAG_PAIRED != 0 && (f1 & bam::FLAG_READ1 == 0 || f2 & bam::FLAG_READ2 == 0) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("SAM/BAM mates are not adjacent read1/read2 records near {}", r1.name)));
+                }
+                Ok((Some(r1), Some(r2)))
+            }
+            (a, b) => Ok((a.map(|r| r.0), b.map(|r| r.0))),
+        }
+    }
+
+    /// One FASTQ record of four lines; with `multi_line` the sequence and quality may be wrapped
+    /// (the sequence ends at the `+` line, the quality once it is as long as the sequence).
+    /// A truncated record, or a quality of another length than the sequence, is an error.
+    fn next_fastq(reader: &mut Box<dyn BufRead + Send>, multi_line: bool) -> io::Result<Option<FastqRecord>> {
         let mut name = String::new();
         let mut seq = String::new();
         let mut plus = String::new();
         let mut qual = String::new();
 
-        if self.reader.read_line(&mut name)? == 0 { return Ok(None); }
-        if self.reader.read_line(&mut seq)? == 0 { return Ok(None); }
-        if self.reader.read_line(&mut plus)? == 0 { return Ok(None); }
-        if self.reader.read_line(&mut qual)? == 0 { return Ok(None); }
+        // blank lines at the end of the input
+        loop {
+            if reader.read_line(&mut name)? == 0 { return Ok(None); }
+            if !name.trim_end().is_empty() { break; }
+            name.clear();
+        }
+        Self::chomp(&mut name);
+        if !name.starts_with('@') {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("FASTQ header expected, found: {}", name)));
+        }
+        let truncated = || io::Error::new(io::ErrorKind::InvalidData, format!("FASTQ record {} is truncated", name));
 
-        if name.ends_with('\n') { name.pop(); }
-        if name.ends_with('\r') { name.pop(); }
-        if seq.ends_with('\n') { seq.pop(); }
-        if seq.ends_with('\r') { seq.pop(); }
-        if plus.ends_with('\n') { plus.pop(); }
-        if plus.ends_with('\r') { plus.pop(); }
-        if qual.ends_with('\n') { qual.pop(); }
-        if qual.ends_with('\r') { qual.pop(); }
+        if reader.read_line(&mut seq)? == 0 { return Err(truncated()); }
+        Self::chomp(&mut seq);
+        loop {
+            if reader.read_line(&mut plus)? == 0 { return Err(truncated()); }
+            if plus.starts_with('+') { break; }
+            if !multi_line {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("FASTQ record {} has no `+` line", name)));
+            }
+            Self::chomp(&mut plus);
+            seq.push_str(&plus);
+            plus.clear();
+        }
+        Self::chomp(&mut plus);
+        loop {
+            if reader.read_line(&mut qual)? == 0 { return Err(truncated()); }
+            Self::chomp(&mut qual);
+            if !multi_line || qual.len() >= seq.len() { break; }
+        }
+        if qual.len() != seq.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("FASTQ record {} has {} quality scores for {} bases", name, qual.len(), seq.len())));
+        }
 
         Ok(Some(FastqRecord { name, seq, plus, qual }))
     }
+
+    fn chomp(line: &mut String) {
+        if line.ends_with('\n') { line.pop(); }
+        if line.ends_with('\r') { line.pop(); }
+    }
+
+    /// One FASTA record; the sequence may span several lines up to the next `>`.
+    fn next_fasta(reader: &mut Box<dyn BufRead + Send>, qual: char) -> io::Result<Option<FastqRecord>> {
+        let mut header = String::new();
+        loop {
+            if reader.read_line(&mut header)? == 0 { return Ok(None); }
+            if !header.trim_end().is_empty() { break; }
+            header.clear();
+        }
+        let Some(name) = header.trim_end().strip_prefix('>') else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("FASTA header expected, found: {}", header.trim_end())));
+        };
+        let name = format!("@{}", name);
+
+        let mut seq = String::new();
+        let mut line = String::new();
+        loop {
+            let buf = reader.fill_buf()?;
+            if buf.is_empty() || buf[0] == b'>' { break; }
+            line.clear();
+            reader.read_line(&mut line)?;
+            seq.push_str(line.trim_end());
+        }
+        let qual = std::iter::repeat_n(qual, seq.len()).collect();
+        Ok(Some(FastqRecord { name, seq, plus: "+".to_string(), qual }))
+    }
+}
+
+enum Sink {
+    Plain(Box<dyn Write>),
+    Blocks(BlockWriter<Box<dyn Write>>),
+    External(ExternalCompressor),
+}
+
+/// Record encoding of an output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordFormat {
+    Fastq,
+    /// sequence only, qualities are dropped
+    Fasta,
+    /// unaligned BAM; both mates of a pair go to the same file
+    Bam,
+}
+
+impl RecordFormat {
+    pub fn for_path(path: &str) -> Self {
+        if path.ends_with(".bam") {
+            return RecordFormat::Bam;
+        }
+        let base = [".gz", ".bgz", ".zst"].iter().fold(path, |p, ext| p.strip_suffix(ext).unwrap_or(p));
+        if [".fa", ".fasta", ".fna", ".fas"].iter().any(|ext| base.ends_with(ext)) {
+            RecordFormat::Fasta
+        } else {
+            RecordFormat::Fastq
+        }
+    }
 }
 
 pub struct Writer {
-    writer: Box<dyn Write>,
+    sink: Sink,
+    format: RecordFormat,
 }
 
 impl Writer {
     pub fn new(path: Option<&str>, stdout: bool, compression_level: u32) -> io::Result<Self> {
-        let writer: Box<dyn Write> = if stdout || path == Some("/dev/stdout") || path.is_none() {
-            Box::new(io::stdout())
-        } else {
-            let p = path.unwrap();
-            let f = File::create(p)?;
-            if p.ends_with(".gz") {
-                let enc = GzEncoder::new(f, Compression::new(compression_level));
-                Box::new(enc)
-            } else {
-                Box::new(f)
+        let writer: Box<dyn Write> = match path {
+            Some(p) if !stdout && p != "/dev/stdout" => {
+                let f = File::create(p)?;
+                if p.ends_with(".gz") {
+                    let enc = GzEncoder::new(f, Compression::new(compression_level));
+                    Box::new(enc)
+                } else {
+                    Box::new(f)
+                }
             }
+            _ => Box::new(io::stdout()),
+        };
+        Ok(Self { sink: Sink::Plain(writer), format: RecordFormat::Fastq })
+    }
+
+    /// Writer whose output is compressed in `format` blocks on a shared pool.
+    /// With `index`, BGZF block offsets are written next to the output as `<path>.gzi`.
+    pub fn with_pool(path: Option<&str>, stdout: bool, pool: Arc<CompressionPool>, format: BlockFormat, index: bool) -> io::Result<Self> {
+        let (inner, index_path): (Box<dyn Write>, Option<String>) = match path {
+            Some(p) if !stdout && p != "/dev/stdout" => (Box::new(BufWriter::new(File::create(p)?)), Some(format!("{}.gzi", p))),
+            _ => (Box::new(io::stdout()), None),
+        };
+        let mut w = BlockWriter::new(inner, pool, format);
+        if let (true, BlockFormat::Bgzf, Some(p)) = (index, format, index_path) {
+            w = w.with_index(p);
+        }
+        Ok(Self { sink: Sink::Blocks(w), format: RecordFormat::Fastq })
+    }
+
+    /// Writer piping into an external compressor process (`pigz`/`bgzip`/`zstd`, chosen by `format`).
+    pub fn external(path: Option<&str>, stdout: bool, format: BlockFormat, opts: &CompressOptions, threads: usize, index: bool) -> io::Result<Self> {
+        let path = match path {
+            Some(p) if !stdout && p != "/dev/stdout" => Some(p),
+            _ => None,
         };
-        Ok(Self { writer })
+        let child = ExternalCompressor::spawn(path, format, opts, threads, index)?;
+        Ok(Self { sink: Sink::External(child), format: RecordFormat::Fastq })
+    }
+
+    /// Write records as `format`. BAM needs its header first, see `into_bam`.
+    pub fn with_format(mut self, format: RecordFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Switch to unaligned BAM records and write the BAM header (`header` is SAM header text, e.g. from a uBAM input).
+    /// The underlying stream must be BGZF.
+    pub fn into_bam(mut self, header: &str) -> io::Result<Self> {
+        BamWriter::write_header(&mut self, header)?;
+        self.format = RecordFormat::Bam;
+        Ok(self)
     }
 
     pub fn write_record(&mut self, rec: &FastqRecord) -> io::Result<()> {
-        use std::io::Write;
-        self.writer.write_all(rec.name.as_bytes())?; self.writer.write_all(b"\n")?;
-        self.writer.write_all(rec.seq.as_bytes())?;  self.writer.write_all(b"\n")?;
-        self.writer.write_all(rec.plus.as_bytes())?; self.writer.write_all(b"\n")?;
-        self.writer.write_all(rec.qual.as_bytes())?; self.writer.write_all(b"\n")?;
+        if self.format == RecordFormat::Bam {
+            return BamWriter::write_record(self, rec, bam::FLAG_UNMAPPED);
+        }
+        if self.format == RecordFormat::Fasta {
+            self.write_all(b">")?; self.write_all(rec.name.strip_prefix('@').unwrap_or(&rec.name).as_bytes())?; self.write_all(b"\n")?;
+            self.write_all(rec.seq.as_bytes())?;  self.write_all(b"\n")?;
+            return Ok(());
+        }
+        self.write_all(rec.name.as_bytes())?; self.write_all(b"\n")?;
+        self.write_all(rec.seq.as_bytes())?;  self.write_all(b"\n")?;
+        self.write_all(rec.plus.as_bytes())?; self.write_all(b"\n")?;
+        self.write_all(rec.qual.as_bytes())?; self.write_all(b"\n")?;
         Ok(())
     }
+
+    /// Both mates into this one output (interleaved FASTQ, or a paired uBAM).
+    pub fn write_pair(&mut self, r1: &FastqRecord, r2: &FastqRecord) -> io::Result<()> {
+        if self.format == RecordFormat::Bam {
+            let flag = bam::FLAG_PAIRED | bam::FLAG_UNMAPPED | bam::FLAG_MATE_UNMAPPED;
+            BamWriter::write_record(self, r1, flag | bam::FLAG_READ1)?;
+            return BamWriter::write_record(self, r2, flag | bam::FLAG_READ2);
+        }
+        self.write_record(r1)?;
+        self.write_record(r2)
+    }
+
+    /// Flush everything and close the compressed stream.
+    pub fn finish(self) -> io::Result<()> {
+        match self.sink {
+            Sink::Plain(mut w) => w.flush(),
+            Sink::Blocks(w) => w.finish().map(|_| ()),
+            Sink::External(w) => w.finish(),
+        }
+    }
 }
 
 impl Write for Writer {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.writer.write(buf) }
-    fn flush(&mut self) -> io::Result<()> { self.writer.flush() }
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.sink {
+            Sink::Plain(w) => w.write(buf),
+            Sink::Blocks(w) => w.write(buf),
+            Sink::External(w) => w.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.sink {
+            Sink::Plain(w) => w.flush(),
+            Sink::Blocks(w) => w.flush(),
+            Sink::External(w) => w.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(name: &str, data: &str, multi_line: bool) -> io::Result<Vec<FastqRecord>> {
+        let path = std::env::temp_dir().join(format!("fastp_rs_reader_test_{}_{name}.fq", std::process::id()));
+        std::fs::write(&path, data).unwrap();
+        let mut reader = Reader::new(path.to_str(), false, 1).unwrap().with_multi_line(multi_line);
+        let out = std::iter::from_fn(|| reader.next_record().transpose()).collect();
+        std::fs::remove_file(&path).unwrap();
+        out
+    }
+
+    #[test]
+    fn wrapped_records_only_in_multi_line_mode() {
+        let wrapped = "@r1\nACGT\nACGT\n+\nIIII\nIIII\n@r2\nAC\n+\nII\n";
+        let recs = read_all("wrapped", wrapped, true).unwrap();
+        assert_eq!(recs.len(), 2);
+        assert_eq!((recs[0].seq.as_str(), recs[0].qual.as_str()), ("ACGTACGT", "IIIIIIII"));
+        assert!(read_all("wrapped_single", wrapped, false).is_err());
+        assert_eq!(read_all("plain", "@r1\nACGT\n+\nIIII\n\n", false).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn malformed_records_are_errors() {
+        // a short quality line must not swallow the records after it
+        let short_qual = "@r1\nACGTACGT\n+\nIIII\n@r2\nACGTACGT\n+\nIIIIIIII\n@r3\nACGTACGT\n+\nIIIIIIII\n";
+        let cases = [
+            ("short_qual", short_qual),
+            ("long_qual", "@r1\nACGT\n+\nIIIII\n"),
+            ("truncated", "@r1\nACGTACGT\n+\n"),
+            ("no_plus", "@r1\nACGT\nIIII\n@r2\n"),
+            ("no_header", "r1\nACGT\n+\nIIII\n"),
+        ];
+        for (name, data) in cases {
+            for multi_line in [false, true] {
+                let err = read_all(name, data, multi_line).unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{name} {multi_line}");
+            }
+        }
+    }
 }
@@ -0,0 +1,90 @@
+use std::io;
+use super::FastqRecord;
+
+/// Illumina's 8-level binning (RTA): Q2-9 -> 6, Q10-19 -> 15, Q20-24 -> 22, Q25-29 -> 27,
+/// Q30-34 -> 33, Q35-39 -> 37, Q40 and above -> 40. Q0/Q1 (no-calls) are left as they are.
+pub const ILLUMINA8: &str = "2-9:6,10-19:15,20-24:22,25-29:27,30-34:33,35-39:37,40-:40";
+
+/// Phred+33 can encode up to Q93 ('~').
+const MAX_QUAL: u8 = 93;
+
+/// Output quality binning (`--quality_bins`): maps each phred score to the value of its bin.
+/// Scores not covered by any bin are written unchanged.
+pub struct QualityBins {
+    /// phred+33 character -> binned phred+33 character
+    table: [u8; 256],
+    scheme: String,
+}
+
+impl QualityBins {
+    /// Parse a bin table: `illumina8`, or comma-separated `low-high:value` entries
+    /// (`low-` for an open upper end, `q:value` for a single score), e.g. `0-19:10,20-:30`.
+    pub fn parse(spec: &str) -> io::Result<Self> {
+        let scheme = if spec.eq_ignore_ascii_case("illumina8") { ILLUMINA8 } else { spec };
+        let mut table: [u8; 256] = std::array::from_fn(|i| i as u8);
+        for entry in scheme.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let bad = || io::Error::new(io::ErrorKind::InvalidInput, format!("bad quality bin {} in --quality_bins (expected low-high:value)", entry));
+            let (range, value) = entry.split_once(':').ok_or_else(bad)?;
+            let value: u8 = value.trim().parse().map_err(|_| bad())?;
+            let (low, high) = match range.split_once('-') {
+                Some((low, "")) => (low.trim().parse().map_err(|_| bad())?, MAX_QUAL),
+                Some((low, high)) => (low.trim().parse().map_err(|_| bad())?, high.trim().parse().map_err(|_| bad())?),
+                None => {
+                    let q: u8 = range.trim().parse().map_err(|_| bad())?;
+                    (q, q)
+                }
+            };
+            if low > high || high > MAX_QUAL || value > MAX_QUAL {
+                return Err(bad());
+            }
+            for q in low..=high {
+                table[(q + 33) as usize] = value + 33;
+            }
+        }
+        Ok(Self { table, scheme: scheme.to_string() })
+    }
+
+    /// The bin table in effect, for the report.
+    pub fn scheme(&self) -> &str {
+        &self.scheme
+    }
+
+    /// Rewrite the qualities of `rec` into their bins.
+    pub fn apply(&self, rec: &mut FastqRecord) {
+        if rec.qual.bytes().any(|q| self.table[q as usize] != q) {
+            rec.qual = rec.qual.bytes().map(|q| self.table[q as usize] as char).collect();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn binned(bins: &QualityBins, qual: &str) -> String {
+        let mut rec = FastqRecord { name: "@r".into(), seq: "A".repeat(qual.len()), plus: "+".into(), qual: qual.into() };
+        bins.apply(&mut rec);
+        rec.qual
+    }
+
+    #[test]
+    fn illumina8_bins() {
+        let bins = QualityBins::parse("Illumina8").unwrap();
+        assert_eq!(bins.scheme(), ILLUMINA8);
+        // Q0 Q1 Q2 Q9 Q10 Q19 Q20 Q24 Q25 Q29 Q30 Q34 Q35 Q39 Q40 Q41
+        assert_eq!(binned(&bins, "!\"#*+459:>?CDHIJ"), "!\"''0077<<BBFFII");
+    }
+
+    #[test]
+    fn custom_bins_and_uncovered_scores() {
+        let bins = QualityBins::parse("0-19:10, 30:35 ,40-:40").unwrap();
+        assert_eq!(binned(&bins, "!4?5I~"), "++D5II");
+    }
+
+    #[test]
+    fn rejects_malformed_bins() {
+        for spec in ["10", "20-10:15", "0-19", "0-100:10", "0-19:94", "a-b:1"] {
+            assert!(QualityBins::parse(spec).is_err(), "{spec}");
+        }
+    }
+}
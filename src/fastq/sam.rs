@@ -0,0 +1,158 @@
+use std::io::{self, BufRead};
+use super::FastqRecord;
+use super::bam::{restore_orientation, FLAG_PAIRED, FLAG_SECONDARY, FLAG_SUPPLEMENTARY, MISSING_QUAL};
+
+/// Header lines a SAM file can start with.
+const HEADER_CODES: [&[u8]; 5] = [b"@HD\t", b"@SQ\t", b"@RG\t", b"@PG\t", b"@CO\t"];
+
+/// Mandatory fields of a SAM record.
+const MANDATORY_FIELDS: usize = 11;
+
+/// Whether `start` (the beginning of a decompressed input) is SAM text: a header line, or a
+/// headerless record with all mandatory fields and a numeric flag.
+pub fn is_sam(start: &[u8]) -> bool {
+    if HEADER_CODES.iter().any(|code| start.starts_with(code)) {
+        return true;
+    }
+    let line = start.split(|&b| b == b'\n').next().unwrap_or_default();
+    let mut fields = line.split(|&b| b == b'\t');
+    let flag = fields.nth(1);
+    flag.is_some_and(|f| !f.is_empty() && f.iter().all(u8::is_ascii_digit)) && fields.count() + 2 >= MANDATORY_FIELDS
+}
+
+/// Decodes (unaligned) SAM text into FASTQ records, like `BamReader`: secondary and
+/// supplementary records are skipped, reverse-strand records are restored to their
+/// sequencing orientation and aux tags are kept in the read comment.
+pub struct SamReader {
+    reader: Box<dyn BufRead + Send>,
+    header: String,
+    peeked: Option<(FastqRecord, u16)>,
+    line: String,
+}
+
+impl SamReader {
+    /// `reader` is the decompressed stream, positioned at the first header line or record.
+    pub fn new(mut reader: Box<dyn BufRead + Send>) -> io::Result<Self> {
+        let mut header = String::new();
+        let mut line = String::new();
+        while reader.fill_buf()?.first() == Some(&b'@') {
+            line.clear();
+            reader.read_line(&mut line)?;
+            header.push_str(line.trim_end_matches(['\n', '\r']));
+            header.push('\n');
+        }
+        let mut r = Self { reader, header, peeked: None, line };
+        r.peeked = r.read_primary()?;
+        Ok(r)
+    }
+
+    pub fn header(&self) -> &str {
+        &self.header
+    }
+
+    /// Whether the records are mate pairs (first record has the paired flag).
+    pub fn is_paired(&self) -> bool {
+        matches!(&self.peeked, Some((_, flag)) if flag & FLAG_PAIRED != 0)
+    }
+
+    /// Next primary record and its flag.
+    pub fn next_record(&mut self) -> io::Result<Option<(FastqRecord, u16)>> {
+        match self.peeked.take() {
+            Some(r) => Ok(Some(r)),
+            None => self.read_primary(),
+        }
+    }
+
+    fn read_primary(&mut self) -> io::Result<Option<(FastqRecord, u16)>> {
+        loop {
+            self.line.clear();
+            if self.reader.read_line(&mut self.line)? == 0 {
+                return Ok(None);
+            }
+            let line = self.line.trim_end_matches(['\n', '\r']);
+            if line.is_empty() {
+                continue;
+            }
+            let (rec, flag) = parse_record(line)?;
+            if flag & (FLAG_SECONDARY | FLAG_SUPPLEMENTARY) == 0 {
+                return Ok(Some((rec, flag)));
+            }
+        }
+    }
+}
+
+fn parse_record(line: &str) -> io::Result<(FastqRecord, u16)> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() < MANDATORY_FIELDS {
+        return Err(invalid(format!("SAM record with fewer than {MANDATORY_FIELDS} fields: {line}")));
+    }
+    let flag: u16 = fields[1].parse().map_err(|_| invalid(format!("bad SAM flag: {}", fields[1])))?;
+    let mut seq = match fields[9] {
+        "*" => Vec::new(),
+        s => s.as_bytes().to_ascii_uppercase(),
+    };
+    let mut qual = match fields[10] {
+        "*" => vec![MISSING_QUAL; seq.len()],
+        q if q.len() == seq.len() => q.as_bytes().to_vec(),
+        _ => return Err(invalid(format!("SAM record {} has qualities of another length than its sequence", fields[0]))),
+    };
+    restore_orientation(&mut seq, &mut qual, flag);
+
+    let mut header = format!("@{}", fields[0]);
+    for tag in &fields[MANDATORY_FIELDS..] {
+        header.push('\t');
+        header.push_str(tag);
+    }
+    let rec = FastqRecord {
+        name: header,
+        seq: String::from_utf8(seq).unwrap_or_default(),
+        plus: "+".to_string(),
+        qual: String::from_utf8(qual).unwrap_or_default(),
+    };
+    Ok((rec, flag))
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::bam::{FLAG_READ1, FLAG_READ2};
+
+    const SAM: &str = "@HD\tVN:1.6\tSO:unsorted\n@RG\tID:lane1\n\
+        r1\t77\t*\t0\t0\t*\t*\t0\t0\tACGTN\tIIII#\tRG:Z:lane1\tBC:Z:ACGT\n\
+        r1\t141\t*\t0\t0\t*\t*\t0\t0\tAACGG\t#IIIJ\tRG:Z:lane1\n\
+        r1\t397\t*\t0\t0\t*\t*\t0\t0\tAACGG\t*\n\
+        r2\t93\t*\t0\t0\t*\t*\t0\t0\tAACGG\t*\n";
+
+    #[test]
+    fn detects_sam_text() {
+        assert!(is_sam(SAM.as_bytes()));
+        assert!(is_sam(&SAM.as_bytes()[SAM.find("r1").unwrap()..]));
+        assert!(!is_sam(b"@r1 1:N:0:1\nACGT\n+\nIIII\n"));
+        assert!(!is_sam(b">r1\nACGT\n"));
+    }
+
+    #[test]
+    fn reads_primary_records_in_sequencing_orientation() {
+        let mut reader = SamReader::new(Box::new(io::Cursor::new(SAM.as_bytes().to_vec()))).unwrap();
+        assert_eq!(reader.header(), "@HD\tVN:1.6\tSO:unsorted\n@RG\tID:lane1\n");
+        assert!(reader.is_paired());
+        let out: Vec<_> = std::iter::from_fn(|| reader.next_record().unwrap()).collect();
+        assert_eq!(out.len(), 3, "the secondary record is skipped");
+        assert_eq!(out[0].0.name, "@r1\tRG:Z:lane1\tBC:Z:ACGT");
+        assert_eq!(out[0].1 & FLAG_READ1, FLAG_READ1);
+        assert_eq!((out[1].0.seq.as_str(), out[1].0.qual.as_str()), ("AACGG", "#IIIJ"));
+        assert_eq!(out[1].1 & FLAG_READ2, FLAG_READ2);
+        // reverse strand, no qualities
+        assert_eq!((out[2].0.seq.as_str(), out[2].0.qual.as_str()), ("CCGTT", "?????"));
+    }
+
+    #[test]
+    fn rejects_short_records() {
+        let sam = b"@HD\tVN:1.6\nr1\t4\t*\t0\n".to_vec();
+        assert!(SamReader::new(Box::new(io::Cursor::new(sam))).is_err());
+    }
+}
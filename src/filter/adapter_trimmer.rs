@@ -1,168 +1,470 @@
 use std::cmp;
+use std::collections::BTreeMap;
+use serde::Serialize;
+use crate::config::Cli;
 use crate::fastq::FastqRecord;
 use crate::filter::Report;
 use crate::filter::matcher::Matcher;
+use super::myers::{Myers, MAX_PATTERN_LEN};
+use super::overlap::{OverlapParams, OverlapResult};
+use super::pipeline::TrimState;
+use super::processor::Processor;
 
-pub struct AdapterTrimmer;
+/// An adapter needs at least this many matching bases to be trimmed.
+const MATCH_REQ: usize = 4;
+
+/// Default of `--adapter_bases_per_edit`, upstream's one mismatch for each 8 bases.
+pub const DEFAULT_BASES_PER_EDIT: usize = 8;
+
+/// Default of `--adapter_dimer_max_insert`: a read cut down to this many bases was an adapter dimer.
+pub const DEFAULT_DIMER_MAX_INSERT: usize = 5;
+
+/// Adapter prefixes shared by both mates of common Illumina kits (TruSeq, Nextera): without
+/// adapter sequences, a pair too short to overlap with both mates starting in one is a dimer.
+const DIMER_ADAPTER_PREFIXES: [&[u8]; 2] = [b"AGATCGGAAGAGC", b"CTGTCTCTTATACACATCT"];
+
+/// Trimmed adapters are tallied by their first this many bases.
+const ADAPTER_RECORD_LEN: usize = 32;
+
+/// Trimmed adapters listed in the report; rarer ones are summed as `others`.
+const ADAPTER_COUNTS_REPORTED: usize = 10;
+
+/// What adapter trimming removed from one mate.
+#[derive(Default, Serialize, Clone, Debug)]
+pub struct AdapterStats {
+    /// reads and bases cut where the pair overlaps (no adapter sequence given)
+    pub overlap_trimmed_reads: u64,
+    pub overlap_trimmed_bases: u64,
+    /// reads and bases cut at a match of the adapter sequence
+    pub sequence_trimmed_reads: u64,
+    pub sequence_trimmed_bases: u64,
+    /// trimmed sequences, as upstream's `adapter_counts`; the most frequent ones and `others` once finalized
+    pub adapter_counts: BTreeMap<String, u64>,
+    /// reads by number of trimmed bases
+    pub trimmed_lengths: BTreeMap<usize, u64>,
+}
+
+impl AdapterStats {
+    pub fn merge(&mut self, other: &AdapterStats) {
+        self.overlap_trimmed_reads += other.overlap_trimmed_reads;
+        self.overlap_trimmed_bases += other.overlap_trimmed_bases;
+        self.sequence_trimmed_reads += other.sequence_trimmed_reads;
+        self.sequence_trimmed_bases += other.sequence_trimmed_bases;
+        for (adapter, &n) in &other.adapter_counts {
+            *self.adapter_counts.entry(adapter.clone()).or_insert(0) += n;
+        }
+        for (&len, &n) in &other.trimmed_lengths {
+            *self.trimmed_lengths.entry(len).or_insert(0) += n;
+        }
+    }
+
+    fn add(&mut self, trimmed: &str, by_overlap: bool) {
+        let bases = trimmed.len() as u64;
+        if by_overlap {
+            self.overlap_trimmed_reads += 1;
+            self.overlap_trimmed_bases += bases;
+        } else {
+            self.sequence_trimmed_reads += 1;
+            self.sequence_trimmed_bases += bases;
+        }
+        let recorded = &trimmed[..trimmed.len().min(ADAPTER_RECORD_LEN)];
+        *self.adapter_counts.entry(recorded.to_string()).or_insert(0) += 1;
+        *self.trimmed_lengths.entry(trimmed.len()).or_insert(0) += 1;
+    }
+
+    /// Keep the most frequent adapters, summing the others as `others`.
+    pub fn finalize(&mut self) {
+        if self.adapter_counts.len() <= ADAPTER_COUNTS_REPORTED {
+            return;
+        }
+        let mut counts: Vec<(String, u64)> = std::mem::take(&mut self.adapter_counts).into_iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        let others: u64 = counts[ADAPTER_COUNTS_REPORTED..].iter().map(|(_, n)| n).sum();
+        counts.truncate(ADAPTER_COUNTS_REPORTED);
+        self.adapter_counts = counts.into_iter().collect();
+        self.adapter_counts.insert("others".to_string(), others);
+    }
+}
+
+/// The `adapter` stage.
+pub struct AdapterTrimmer {
+    enabled: bool,
+    /// adapters of R1 and R2
+    adapters: [Option<Adapter>; 2],
+    bases_per_edit: usize,
+    overlap: OverlapParams,
+    /// `DIMER_ADAPTER_PREFIXES`, searched when trimming by overlap
+    dimer_adapters: Vec<Adapter>,
+    dimer_max_insert: usize,
+    /// `--length_required`: shorter adapter-trimmed reads are short inserts
+    length_required: usize,
+    /// drop dimers from the output (`--adapter_dimer_out`)
+    route_dimers: bool,
+}
 
 impl AdapterTrimmer {
-    pub fn trim_by_sequence(
-        rec: &mut FastqRecord,
-        adapter_seq: Option<&str>,
-        report: &mut Report,
-        _is_r2: bool,
-    ) -> bool {
-        let adapter = match adapter_seq {
-            Some(s) if !s.is_empty() => s,
-            _ => return false,
+    pub fn new(c: &Cli) -> Self {
+        let matcher = |adapter: &Option<String>| adapter.as_deref().filter(|a| !a.is_empty()).map(|a| Adapter::new(a.as_bytes()));
+        Self {
+            enabled: !c.disable_adapter_trimming,
+            adapters: [matcher(&c.adapter_sequence), matcher(&c.adapter_sequence_r2)],
+            bases_per_edit: c.adapter_bases_per_edit.max(1),
+            overlap: OverlapParams::new(c),
+            dimer_adapters: DIMER_ADAPTER_PREFIXES.iter().map(|a| Adapter::new(a)).collect(),
+            dimer_max_insert: c.adapter_dimer_max_insert,
+            length_required: c.length_required,
+            route_dimers: c.adapter_dimer_out.is_some(),
+        }
+    }
+
+    /// Without adapter sequences, PE reads are cut where they overlap (the rest is adapter);
+    /// with them, each read is searched for its adapter.
+    fn trim_adapters(&self, state: &mut TrimState, report: &mut Report) {
+        if !self.enabled {
+            return;
+        }
+        if self.adapters.iter().all(Option::is_none) {
+            let Some(r2) = &mut state.r2 else { return };
+            let r1 = &mut state.r1;
+            // reuse the overlap stage's result, or analyze here if that stage is not run before
+            let res = match state.overlap.take() {
+                Some(res) => res,
+                None => self.overlap.analyze(&r1.rec, &r2.rec),
+            };
+            if res.overlapped {
+                Self::trim_by_overlap(&mut r1.rec, &mut r2.rec, &res, report);
+            } else {
+                self.trim_dimer(&mut r1.rec, &mut r2.rec, report);
+            }
+            state.overlap = Some(res);
+            return;
+        }
+        for read in state.reads_mut() {
+            if let Some(adapter) = &self.adapters[read.is_r2 as usize] {
+                self.trim_by_sequence(&mut read.rec, read.is_r2, adapter, report);
+            }
+        }
+    }
+
+    /// Cut each mate after its overlapping bases, at the coordinates the overlap was found at,
+    /// so 3' cuts made since (e.g. polyG with a custom `--trim_order`) do not move the cut.
+    fn trim_by_overlap(r1: &mut FastqRecord, r2: &mut FastqRecord, res: &OverlapResult, report: &mut Report) {
+        if r1.seq.len() > res.r1_range.end {
+            cut_adapter(r1, res.r1_range.end, false, true, report);
+        }
+        // with a non-negative offset R2 ends inside the insert: `r2_range` runs to its end
+        if r2.seq.len() > res.r2_range.end {
+            cut_adapter(r2, res.r2_range.end, true, true, report);
+        }
+    }
+
+    /// A dimer's insert is too short for the overlap analysis: cut a pair whose mates both start
+    /// (within `--adapter_dimer_max_insert` bases) in the same known adapter.
+    fn trim_dimer(&self, r1: &mut FastqRecord, r2: &mut FastqRecord, report: &mut Report) {
+        let dimer_start = |rec: &FastqRecord, adapter: &Adapter| {
+            find_adapter(rec.seq.as_bytes(), adapter, self.bases_per_edit)
+                .map(|pos| pos.max(0) as usize)
+                .filter(|&pos| pos <= self.dimer_max_insert)
         };
+        for adapter in &self.dimer_adapters {
+            if let (Some(p1), Some(p2)) = (dimer_start(r1, adapter), dimer_start(r2, adapter)) {
+                cut_adapter(r1, p1, false, false, report);
+                cut_adapter(r2, p2, true, false, report);
+                return;
+            }
+        }
+    }
 
-        // C++ defaults: matchReq depends on adapter list size, but for single sequence:
-        // default matchReq = 4
-        // allowOneMismatchForEach = 8
-        let match_req = 4;
-        let allow_one_mismatch_for_each = 8;
+    /// Cut `rec` where its adapter starts; the whole read goes when the adapter starts before it.
+    fn trim_by_sequence(&self, rec: &mut FastqRecord, is_r2: bool, adapter: &Adapter, report: &mut Report) {
+        let Some(pos) = find_adapter(rec.seq.as_bytes(), adapter, self.bases_per_edit) else { return };
+        // a negative position: the read starts inside the adapter (e.g. a dimer missing the first A)
+        cut_adapter(rec, pos.max(0) as usize, is_r2, false, report);
+    }
+}
 
-        let rlen = rec.seq.len();
-        let alen = adapter.len();
-        
-        if alen < match_req {
-            return false;
+impl AdapterTrimmer {
+    /// Count a read (pair) whose adapter cut left (almost) nothing as an adapter dimer, or one
+    /// left shorter than `--length_required` as a short insert; dimers are dropped when routed.
+    fn classify_insert(&self, state: &mut TrimState, report: &mut Report) {
+        let insert = state.reads_mut().filter(|read| read.adapter_cut).map(|read| read.rec.seq.len()).min();
+        match insert {
+            Some(len) if len <= self.dimer_max_insert => {
+                report.adapter_dimer_reads += 1;
+                state.adapter_dimer = true;
+                state.discarded = self.route_dimers;
+            }
+            Some(len) if len < self.length_required => report.short_insert_reads += 1,
+            _ => {}
         }
+    }
+}
 
-        let rdata = rec.seq.as_bytes();
-        let adata = adapter.as_bytes();
+/// Cut `rec` to its first `keep` bases, counting the rest as adapter of R1 or R2.
+fn cut_adapter(rec: &mut FastqRecord, keep: usize, is_r2: bool, by_overlap: bool, report: &mut Report) {
+    let trimmed_len = rec.seq.len() - keep;
+    let stats = if is_r2 { &mut report.adapter_r2 } else { &mut report.adapter_r1 };
+    stats.add(&rec.seq[keep..], by_overlap);
+    rec.seq.truncate(keep);
+    rec.qual.truncate(keep);
+    report.adapter_trimmed_reads += 1;
+    report.adapter_trimmed_bases += trimmed_len as u64;
+}
 
-        let mut start = 0isize;
-        if alen >= 16 {
-            start = -4;
-        } else if alen >= 12 {
-            start = -3;
-        } else if alen >= 8 {
-            start = -2;
+/// An adapter to search reads for: its bases, and the Myers matcher of them reversed (the first
+/// `MAX_PATTERN_LEN` bases), as `find_adapter` scans reads backwards.
+struct Adapter {
+    bases: Vec<u8>,
+    matcher: Myers,
+}
+
+impl Adapter {
+    fn new(adapter: &[u8]) -> Self {
+        let reversed: Vec<u8> = adapter[..adapter.len().min(MAX_PATTERN_LEN)].iter().rev().copied().collect();
+        Self { bases: adapter.to_vec(), matcher: Myers::new(&reversed) }
+    }
+}
+
+/// Where the adapter starts in `seq`, as upstream's three scans find it: the leftmost start
+/// with at most one mismatch for each `bases_per_edit` compared bases (the read may start up to
+/// 4 bases into the adapter, negative positions, and the adapter may run off the 3' end),
+/// else the leftmost with one insertion in the read and one mismatch fewer, else the leftmost
+/// with one deletion and one mismatch fewer.
+///
+/// Each of those matches is within `max(compared / bases_per_edit, 1)` edits, so a Myers scan of
+/// the reversed read first picks the starts that can match, and only those are compared base by base.
+/// A match starting at `p` ends at reversed position `rlen - 1 - p`; a 3' partial adapter is
+/// the pattern hanging off the scan start.
+fn find_adapter(seq: &[u8], adapter: &Adapter, bases_per_edit: usize) -> Option<isize> {
+    let (rlen, alen) = (seq.len(), adapter.bases.len());
+    if alen < MATCH_REQ {
+        return None;
+    }
+    let mut starts = Vec::new();
+    let end = adapter.matcher.scan(seq.iter().rev().copied(), true, |t, score| {
+        if t >= MATCH_REQ && score <= cmp::max(cmp::min(t + 1, alen) / bases_per_edit, 1) {
+            starts.push(rlen - 1 - t);
+        }
+    });
+    starts.reverse();
+
+    // adapter dimers: the read starting 1-4 bases into the adapter, tried first
+    let max_skip = if alen >= 16 { 4 } else if alen >= 12 { 3 } else if alen >= 8 { 2 } else { 0 };
+    let skips = (1..=max_skip).rev().filter(|&skip| {
+        rlen + skip > MATCH_REQ && end.prefix_score(adapter.matcher.len() - skip) <= cmp::min(rlen + skip, alen) / bases_per_edit
+    });
+    let mismatches = |p: isize| {
+        let cmplen = cmp::min((rlen as isize - p) as usize, alen);
+        let from = (-p).max(0) as usize;
+        let read = &seq[(from as isize + p) as usize..(cmplen as isize + p) as usize];
+        Matcher::diff_within(&adapter.bases[from..cmplen], read, cmplen / bases_per_edit).is_some()
+    };
+    if let Some(p) = skips.map(|skip| -(skip as isize)).chain(starts.iter().map(|&p| p as isize)).find(|&p| mismatches(p)) {
+        return Some(p);
+    }
+
+    let indel_mismatches = |cmplen: usize| (cmplen / bases_per_edit).saturating_sub(1);
+    let insertion = |p: usize| {
+        let cmplen = cmp::min(rlen - p - 1, alen);
+        p + MATCH_REQ + 1 < rlen && Matcher::match_with_one_insertion(&seq[p..], &adapter.bases, cmplen, indel_mismatches(cmplen))
+    };
+    let deletion = |p: usize| {
+        let cmplen = cmp::min(rlen - p, alen - 1);
+        p + MATCH_REQ < rlen && Matcher::match_with_one_insertion(&adapter.bases, &seq[p..], cmplen, indel_mismatches(cmplen))
+    };
+    starts.iter().copied().find(|&p| insertion(p)).or_else(|| starts.iter().copied().find(|&p| deletion(p))).map(|p| p as isize)
+}
+
+impl Processor for AdapterTrimmer {
+    fn name(&self) -> &'static str {
+        "adapter"
+    }
+
+    fn process(&self, state: &mut TrimState, report: &mut Report) {
+        let before = state.lengths();
+        self.trim_adapters(state, report);
+        for read in state.reads_mut() {
+            read.adapter_cut = read.rec.seq.len() < before[read.is_r2 as usize];
         }
+        self.classify_insert(state, report);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
 
-        let mut pos = 0isize;
-        let mut found = false;
+    const ADAPTER: &[u8] = b"AGATCGGAAGAGCACACGTCTGAACTCCAGTCA";
 
-        // 1. Exact match with hamming distance
-        let end_pos = (rlen as isize) - (match_req as isize);
-        
-        for p in start..end_pos {
-            pos = p;
+    /// The three scans (Hamming, then one insertion, then one deletion) that `find_adapter`
+    /// reproduces, written out over every start as upstream does.
+    fn legacy_adapter_position(rdata: &[u8], adata: &[u8], bases_per_edit: usize) -> Option<isize> {
+        let (rlen, alen) = (rdata.len(), adata.len());
+        if alen < MATCH_REQ {
+            return None;
+        }
+        let start = if alen >= 16 { -4 } else if alen >= 12 { -3 } else if alen >= 8 { -2 } else { 0 };
+        for pos in start..rlen as isize - MATCH_REQ as isize {
             let cmplen = cmp::min((rlen as isize - pos) as usize, alen);
-            let allowed_mismatch = cmplen / allow_one_mismatch_for_each;
+            let allowed = cmplen / bases_per_edit;
             let mut mismatch = 0;
-            let mut matched = true;
-
-            let loop_start = cmp::max(0, -pos) as usize;
-            for i in loop_start..cmplen {
-                if adata[i] != rdata[(i as isize + pos) as usize] {
-                    mismatch += 1;
-                    if mismatch > allowed_mismatch {
-                        matched = false;
-                        break;
-                    }
+            for i in cmp::max(0, -pos) as usize..cmplen {
+                mismatch += (adata[i] != rdata[(i as isize + pos) as usize]) as usize;
+                if mismatch > allowed {
+                    break;
                 }
             }
-            if matched {
-                found = true;
-                break;
+            if mismatch <= allowed {
+                return Some(pos);
+            }
+        }
+        let allowed = |cmplen: usize| (cmplen / bases_per_edit).saturating_sub(1);
+        for pos in 0..rlen.saturating_sub(MATCH_REQ + 1) {
+            let cmplen = cmp::min(rlen - pos - 1, alen);
+            if Matcher::match_with_one_insertion(&rdata[pos..], adata, cmplen, allowed(cmplen)) {
+                return Some(pos as isize);
+            }
+        }
+        for pos in 0..rlen.saturating_sub(MATCH_REQ) {
+            let cmplen = cmp::min(rlen - pos, alen - 1);
+            if Matcher::match_with_one_insertion(adata, &rdata[pos..], cmplen, allowed(cmplen)) {
+                return Some(pos as isize);
             }
         }
+        None
+    }
 
-        // 2. Try one gap (insertion in sequence)
-        if !found {
-             for p in 0..((rlen as isize - match_req as isize - 1) as usize) {
-                pos = p as isize;
-                let cmplen = cmp::min(rlen - p - 1, alen);
-                let allowed_mismatch = if cmplen / allow_one_mismatch_for_each > 0 {
-                    (cmplen / allow_one_mismatch_for_each) - 1
-                } else { 0 };
-                
-                // matchWithOneInsertion(rdata, adata, ...) -> insertion in rdata
-                // C++: matchWithOneInsertion(rdata, adata, cmplen, allowedMismatch)
-                // Rust Matcher expects (ins_data, normal_data)
-                // Here rdata has insertion relative to adata? 
-                // C++: Matcher::matchWithOneInsertion(rdata, adata, cmplen, allowedMismatch);
-                // "rdata" is passed as insData. So read has insertion.
-                let matched = Matcher::match_with_one_insertion(
-                    &rdata[p..], 
-                    adata, 
-                    cmplen, 
-                    allowed_mismatch
-                );
-                
-                if matched {
-                    found = true;
-                    // hasInsertion = true;
-                    break;
-                }
+    fn base(rng: &mut StdRng) -> u8 {
+        b"ACGTN"[if rng.random_bool(0.01) { 4 } else { rng.random_range(0..4) }]
+    }
+
+    /// An insert, then `adapter` with substitutions and indels at `error_percent`, then random bases.
+    fn simulated_read(rng: &mut StdRng, adapter: &[u8], error_percent: u32) -> Vec<u8> {
+        let insert_len = rng.random_range(0..=150);
+        let mut seq: Vec<u8> = (0..insert_len).map(|_| base(rng)).collect();
+        for &b in adapter {
+            let roll = rng.random_range(0..100 * 3);
+            if roll >= error_percent * 3 {
+                seq.push(b);
+                continue;
+            }
+            match roll % 3 {
+                0 => seq.push(base(rng)),
+                1 => {}
+                _ => seq.extend([base(rng), b]),
             }
         }
+        seq.extend((0..20).map(|_| base(rng)));
+        seq.truncate(rng.random_range(MATCH_REQ + 1..=151).min(seq.len()));
+        seq
+    }
 
-        // 3. Try deletion in sequence (insertion in adapter)
-        if !found {
-            for p in 0..((rlen as isize - match_req as isize) as usize) {
-                pos = p as isize;
-                let cmplen = cmp::min(rlen - p, alen - 1);
-                let allowed_mismatch = if cmplen / allow_one_mismatch_for_each > 0 {
-                    (cmplen / allow_one_mismatch_for_each) - 1
-                } else { 0 };
-
-                // C++: Matcher::matchWithOneInsertion(adata, rdata, cmplen, allowedMismatch);
-                // "adata" passed as insData. Adapter has insertion (meaning read has deletion).
-                let matched = Matcher::match_with_one_insertion(
-                    adata,
-                    &rdata[p..],
-                    cmplen,
-                    allowed_mismatch
-                );
-
-                if matched {
-                    found = true;
-                    // hasDeletion = true;
-                    break;
+    #[test]
+    fn positions_match_the_legacy_scans() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let long: Vec<u8> = ADAPTER.iter().chain(ADAPTER).copied().collect();
+        for adapter in [ADAPTER, &ADAPTER[..8], &ADAPTER[..12], &ADAPTER[..5], &long] {
+            let compiled = Adapter::new(adapter);
+            for bases_per_edit in [4, DEFAULT_BASES_PER_EDIT, 12] {
+                for error_percent in [0, 3, 10] {
+                    for _ in 0..1000 {
+                        let seq = simulated_read(&mut rng, adapter, error_percent);
+                        assert_eq!(
+                            find_adapter(&seq, &compiled, bases_per_edit),
+                            legacy_adapter_position(&seq, adapter, bases_per_edit),
+                            "{} in {}",
+                            String::from_utf8_lossy(adapter),
+                            String::from_utf8_lossy(&seq)
+                        );
+                    }
                 }
             }
         }
+    }
 
-        if found {
-            if pos < 0 {
-                // Adapter starts before read start (partial match at beginning?)
-                // logic in C++:
-                // string adapter = adapterseq.substr(0, alen+pos);
-                // r->mSeq->resize(0);
-                // r->mQuality->resize(0);
-                // Actually if pos < 0, it means the adapter match starts BEFORE the read. 
-                // Wait, if pos is negative, rlen-pos is > rlen. 
-                // In C++, if pos < 0, it empties the read?
-                // "Illumina adapter dimer usually have the first A skipped"
-                // If the adapter is found at -4, it means the read STARTS with the adapter (minus first 4 bases).
-                // So the whole read is adapter?
-                // Yes, resize(0).
-                
-                // Capture trimmed bases for reporting
-                // In C++: adapter = adapterseq.substr(0, alen+pos)
-                // But we just track counts here for now
-                let trimmed_len = rec.seq.len();
-                rec.seq.clear();
-                rec.qual.clear();
-                
-                report.adapter_trimmed_reads += 1;
-                report.adapter_trimmed_bases += trimmed_len as u64;
-            } else {
-                let p = pos as usize;
-                let trimmed_len = rec.seq.len() - p;
-                rec.seq.truncate(p);
-                rec.qual.truncate(p);
-                
-                report.adapter_trimmed_reads += 1;
-                report.adapter_trimmed_bases += trimmed_len as u64;
-            }
-            return true;
+    #[test]
+    fn detects_dimers_without_adapter_sequences() {
+        use clap::Parser;
+        let cli = Cli::parse_from(["fastp_rs"]);
+        let trimmer = AdapterTrimmer::new(&cli);
+        let rec = |seq: &str| FastqRecord { name: "r".into(), seq: seq.into(), plus: "+".into(), qual: "I".repeat(seq.len()) };
+        let r2_adapter = "AGATCGGAAGAGCGTCGTGTAGGGAAAGAGTGTAGATCTCGGTGGTCGCCGTATCATT";
+        let mut report = Report::default();
+        let mut state = TrimState::new(rec(&format!("ACG{}", std::str::from_utf8(ADAPTER).unwrap())), Some(rec(&format!("CGT{r2_adapter}"))));
+        trimmer.process(&mut state, &mut report);
+        assert_eq!(state.lengths(), [3, 3]);
+        assert!(state.adapter_dimer);
+        assert_eq!(report.adapter_dimer_reads, 1);
+
+        // a pair with a real (non-overlapping) insert is left alone
+        let mut report = Report::default();
+        let mut state = TrimState::new(rec(&"ACGTTGCA".repeat(8)), Some(rec(&format!("CGT{r2_adapter}"))));
+        trimmer.process(&mut state, &mut report);
+        assert_eq!(state.lengths(), [64, 3 + r2_adapter.len()]);
+        assert_eq!(report.adapter_dimer_reads, 0);
+    }
+
+    #[test]
+    fn overlap_cut_ignores_poly_g_trimming() {
+        use clap::Parser;
+        use crate::filter::{processor::Registry, Filter};
+        let mut rng = StdRng::seed_from_u64(36);
+        let insert: Vec<u8> = (0..100).map(|_| base(&mut rng)).collect();
+        let rc: Vec<u8> = insert.iter().rev().map(|&b| match b { b'A' => b'T', b'T' => b'A', b'C' => b'G', _ => b'C' }).collect();
+        let r2_adapter = b"AGATCGGAAGAGCGTCGTGTAGGGAAAGAGTG";
+        let rec = |parts: &[&[u8]]| {
+            let seq = String::from_utf8(parts.concat()).unwrap();
+            FastqRecord { name: "@r".into(), qual: "I".repeat(seq.len()), seq, plus: "+".into() }
+        };
+        let tail = [b'G'; 17];
+        for args in [&[][..], &["--trim_poly_g"], &["--trim_poly_g", "--trim_order", "fixed,quality,overlap,poly_g,adapter"]] {
+            let cli = Cli::parse_from(["fastp_rs"].iter().chain(args));
+            let filter = Filter::new(cli, &Registry::builtin()).unwrap();
+            let (r1, r2) = filter
+                .trim_pair(rec(&[&insert, ADAPTER, &tail]), rec(&[&rc, r2_adapter, &tail]), &mut Report::default())
+                .unwrap();
+            assert_eq!((r1.seq.as_bytes(), r2.seq.as_bytes()), (&insert[..], &rc[..]), "{args:?}: {} and {} bases left", r1.seq.len(), r2.seq.len());
         }
+    }
 
-        false
+    #[test]
+    fn finds_whole_partial_and_dimer_adapters() {
+        let adapter = Adapter::new(ADAPTER);
+        let insert = b"TTGCAACGTTGCAACGTTGCAACG";
+        let read = |tail: &[u8]| [&insert[..], tail].concat();
+        // whole adapter, one substitution, and a 3' prefix of it
+        assert_eq!(find_adapter(&read(ADAPTER), &adapter, 8), Some(24));
+        let mut mutated = ADAPTER.to_vec();
+        mutated[10] = b'T';
+        assert_eq!(find_adapter(&read(&mutated), &adapter, 8), Some(24));
+        assert_eq!(find_adapter(&read(&ADAPTER[..6]), &adapter, 8), Some(24));
+        // one deleted base is found by the deletion scan
+        let deleted = [&ADAPTER[..12], &ADAPTER[13..]].concat();
+        assert_eq!(find_adapter(&read(&deleted), &adapter, 8), Some(24));
+        // a dimer missing the adapter's first two bases
+        assert_eq!(find_adapter(&ADAPTER[2..], &adapter, 8), Some(-2));
+        assert_eq!(find_adapter(insert, &adapter, 8), None);
+        assert_eq!(find_adapter(b"", &adapter, 8), None);
+    }
+
+    /// `cargo test --release -- --ignored adapter_search_speed --nocapture`: time per read of
+    /// the Myers prefilter and the plain scans, on reads with and without adapter.
+    #[test]
+    #[ignore]
+    fn adapter_search_speed() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut reads: Vec<Vec<u8>> = (0..50_000).map(|_| simulated_read(&mut rng, ADAPTER, 3)).collect();
+        reads.extend((0..50_000).map(|_| (0..150).map(|_| base(&mut rng)).collect()));
+        let adapter = Adapter::new(ADAPTER);
+        let time = |name: &str, find: &dyn Fn(&[u8]) -> Option<isize>| {
+            let start = Instant::now();
+            let found = reads.iter().filter(|seq| find(seq).is_some()).count();
+            println!("{name}: {:.0} ns/read, {found} found", start.elapsed().as_nanos() as f64 / reads.len() as f64);
+        };
+        time("myers", &|seq| find_adapter(seq, &adapter, DEFAULT_BASES_PER_EDIT));
+        time("legacy", &|seq| legacy_adapter_position(seq, ADAPTER, DEFAULT_BASES_PER_EDIT));
     }
 }
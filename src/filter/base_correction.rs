@@ -1,54 +1,95 @@
+use std::collections::BTreeMap;
+use serde::Serialize;
 use crate::fastq::FastqRecord;
 
+/// Default cap of merged qualities (`--correction_max_qual`), the highest Illumina score.
+pub const DEFAULT_MAX_MERGED_QUAL: u8 = 41;
+
+/// What overlap correction changed in one mate.
+#[derive(Default, Serialize, Clone, Debug)]
+pub struct CorrectionStats {
+    /// mismatches overwritten with the other mate's base
+    pub corrected_bases: u64,
+    /// Ns filled from the other mate
+    pub n_filled_bases: u64,
+    /// agreeing bases whose quality was raised to the merged one
+    pub boosted_bases: u64,
+    /// corrected and filled bases by the base written
+    pub by_base: BTreeMap<char, u64>,
+}
+
+impl CorrectionStats {
+    pub fn merge(&mut self, other: &CorrectionStats) {
+        self.corrected_bases += other.corrected_bases;
+        self.n_filled_bases += other.n_filled_bases;
+        self.boosted_bases += other.boosted_bases;
+        for (&base, &n) in &other.by_base {
+            *self.by_base.entry(base).or_insert(0) += n;
+        }
+    }
+
+    fn changed(&mut self, base: u8, was_n: bool) {
+        if was_n {
+            self.n_filled_bases += 1;
+        } else {
+            self.corrected_bases += 1;
+        }
+        *self.by_base.entry(base as char).or_insert(0) += 1;
+    }
+}
+
 pub struct BaseCorrector;
 
 impl BaseCorrector {
-    pub fn correct(r1: &mut FastqRecord, r2: &mut FastqRecord, offset: i32, overlap_len: usize) {
+    /// Reconcile the overlapping part of a pair, base by base:
+    /// - on a mismatch the mate with the higher quality wins, base and quality,
+    /// - an N is filled from the other mate, whatever the qualities,
+    /// - agreeing bases both get the merged quality `q1 + q2`, capped at `max_qual` (0 = no merging).
+    ///
+    /// `stats` are those of R1 and R2.
+    pub fn correct(r1: &mut FastqRecord, r2: &mut FastqRecord, offset: i32, overlap_len: usize, max_qual: u8, stats: &mut [CorrectionStats; 2]) {
         let len2 = r2.seq.len();
         let mut s1 = r1.seq.as_bytes().to_vec();
         let mut q1 = r1.qual.as_bytes().to_vec();
         let mut s2 = r2.seq.as_bytes().to_vec();
         let mut q2 = r2.qual.as_bytes().to_vec();
 
-        if offset >= 0 {
-            let off = offset as usize;
-            for i in 0..overlap_len {
-                let i1 = off + i;
-                let j = len2 - 1 - i;
-                let b1 = s1[i1];
-                let b2 = s2[j];
-                let rc_b2 = complement(b2);
-                if b1 != rc_b2 {
-                    let q1v = q1[i1].saturating_sub(33);
-                    let q2v = q2[j].saturating_sub(33);
-                    if q1v >= q2v {
-                        s2[j] = complement(b1);
-                        q2[j] = q1[i1];
-                    } else {
-                        s1[i1] = rc_b2;
-                        q1[i1] = q2[j];
-                    }
-                }
+        // R1 position `start1 + i` faces R2 position `len2 - 1 - skip2 - i`
+        let (start1, skip2) = if offset >= 0 { (offset as usize, 0) } else { (0, (-offset) as usize) };
+        for i in 0..overlap_len {
+            let i1 = start1 + i;
+            let j = len2 - 1 - skip2 - i;
+            let b1 = s1[i1];
+            let rc_b2 = complement(s2[j]);
+            let (n1, n2) = (b1 == b'N', rc_b2 == b'N');
+            if n1 && n2 {
+                continue;
             }
-        } else {
-            let k = (-offset) as usize;
-            for i in 0..overlap_len {
-                let i1 = i;
-                let j = len2 - 1 - (k + i);
-                let b1 = s1[i1];
-                let b2 = s2[j];
-                let rc_b2 = complement(b2);
-                if b1 != rc_b2 {
-                    let q1v = q1[i1].saturating_sub(33);
-                    let q2v = q2[j].saturating_sub(33);
-                    if q1v >= q2v {
-                        s2[j] = complement(b1);
-                        q2[j] = q1[i1];
-                    } else {
-                        s1[i1] = rc_b2;
-                        q1[i1] = q2[j];
+            if b1 == rc_b2 {
+                if max_qual > 0 {
+                    // never below either mate's own quality
+                    let merged = (q1[i1].saturating_sub(33) + q2[j].saturating_sub(33)).min(max_qual) + 33;
+                    let merged = merged.max(q1[i1]).max(q2[j]);
+                    let [stats1, stats2] = stats;
+                    for (q, s) in [(&mut q1[i1], stats1), (&mut q2[j], stats2)] {
+                        if merged > *q {
+                            *q = merged;
+                            s.boosted_bases += 1;
+                        }
                     }
                 }
+                continue;
+            }
+            let q1v = q1[i1].saturating_sub(33);
+            let q2v = q2[j].saturating_sub(33);
+            if n2 || (!n1 && q1v >= q2v) {
+                s2[j] = complement(b1);
+                q2[j] = q1[i1];
+                stats[1].changed(s2[j], n2);
+            } else {
+                s1[i1] = rc_b2;
+                q1[i1] = q2[j];
+                stats[0].changed(rc_b2, n1);
             }
         }
 
@@ -69,3 +110,55 @@ fn complement(b: u8) -> u8 {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rec(seq: &str, qual: &str) -> FastqRecord {
+        FastqRecord { name: "@r".into(), seq: seq.into(), plus: "+".into(), qual: qual.into() }
+    }
+
+    #[test]
+    fn corrects_mismatches_fills_ns_and_merges_qualities() {
+        // R1 ACGTN against the reverse complement of R2 TACAT (ATGTA): a low-quality
+        // mismatch in R2 facing R1's C, and an N in R1 facing R2's leading T
+        let mut r1 = rec("ACGTN", "+I++!");
+        let mut r2 = rec("TACAT", "5++#+");
+        let mut stats: [CorrectionStats; 2] = Default::default();
+        BaseCorrector::correct(&mut r1, &mut r2, 0, 5, DEFAULT_MAX_MERGED_QUAL, &mut stats);
+        assert_eq!((r1.seq.as_str(), r1.qual.as_str()), ("ACGTA", "5I555"));
+        assert_eq!((r2.seq.as_str(), r2.qual.as_str()), ("TACGT", "555I5"));
+        assert_eq!((stats[0].corrected_bases, stats[0].n_filled_bases, stats[0].boosted_bases), (0, 1, 3));
+        assert_eq!((stats[1].corrected_bases, stats[1].n_filled_bases, stats[1].boosted_bases), (1, 0, 3));
+        assert_eq!(stats[0].by_base.get(&'A'), Some(&1));
+        assert_eq!(stats[1].by_base.get(&'G'), Some(&1));
+    }
+
+    #[test]
+    fn merging_is_capped_and_can_be_disabled() {
+        let mut r1 = rec("ACGT", "++++");
+        let mut r2 = rec("ACGT", "++++");
+        let mut stats: [CorrectionStats; 2] = Default::default();
+        BaseCorrector::correct(&mut r1, &mut r2, 0, 4, 15, &mut stats);
+        assert_eq!((r1.qual.as_str(), r2.qual.as_str()), ("0000", "0000"));
+
+        let mut r1 = rec("ACGT", "++++");
+        let mut r2 = rec("ACGT", "++++");
+        let mut stats: [CorrectionStats; 2] = Default::default();
+        BaseCorrector::correct(&mut r1, &mut r2, 0, 4, 0, &mut stats);
+        assert_eq!((r1.qual.as_str(), r2.qual.as_str()), ("++++", "++++"));
+        assert_eq!(stats[0].boosted_bases + stats[1].boosted_bases, 0);
+    }
+
+    #[test]
+    fn negative_offsets_skip_the_r2_tail() {
+        // insert GTAC, shorter than both mates: R1 GTACAA, R2 GTACTT (S2 = AAGTAC, offset -2)
+        let mut r1 = rec("GTACAA", "IIIIII");
+        let mut r2 = rec("GTGCTT", "II#III");
+        let mut stats: [CorrectionStats; 2] = Default::default();
+        BaseCorrector::correct(&mut r1, &mut r2, -2, 4, 0, &mut stats);
+        assert_eq!(r1.seq, "GTACAA");
+        assert_eq!((r2.seq.as_str(), r2.qual.as_str()), ("GTACTT", "IIIIII"));
+        assert_eq!(stats[1].corrected_bases, 1);
+    }
+}
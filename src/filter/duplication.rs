@@ -0,0 +1,83 @@
+//! Duplication rate as upstream estimates it: a read (pair) is a duplicate when its sequence
+//! was seen before, looked up in a Bloom filter shared by all workers, so memory stays fixed
+//! whatever the input size at the price of a few false duplicates on very large inputs.
+
+use std::hash::{DefaultHasher, Hasher};
+use std::mem::ManuallyDrop;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::fastq::FastqRecord;
+
+/// Bits of the filter (128 MiB); about 0.2% false duplicates at 50M reads.
+const FILTER_BITS: usize = 1 << 30;
+
+/// Bits set per read.
+const HASHES: u64 = 3;
+
+pub struct Duplication {
+    bits: Vec<AtomicU64>,
+}
+
+impl Duplication {
+    pub fn new() -> Self {
+        Self::with_bits(FILTER_BITS)
+    }
+
+    /// A filter of `bits` bits, a power of two.
+    fn with_bits(bits: usize) -> Self {
+        // zeroed allocation, so pages are only committed once reads touch them
+        let words = ManuallyDrop::new(vec![0u64; bits / 64]);
+        // SAFETY: AtomicU64 has the same size, alignment and bit validity as u64, and the
+        // vector's allocation is handed over whole (pointer, length and capacity)
+        let bits = unsafe { Vec::from_raw_parts(words.as_ptr() as *mut AtomicU64, words.len(), words.capacity()) };
+        Self { bits }
+    }
+
+    /// Record a read (pair) and say whether its sequence was seen before.
+    pub fn seen(&self, r1: &FastqRecord, r2: Option<&FastqRecord>) -> bool {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(r1.seq.as_bytes());
+        if let Some(r2) = r2 {
+            hasher.write_u8(0);
+            hasher.write(r2.seq.as_bytes());
+        }
+        let hash = hasher.finish();
+        // double hashing: the i-th bit is at h1 + i * h2
+        let (h1, h2) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        let mask = (self.bits.len() * 64 - 1) as u64;
+        let mut seen = true;
+        for i in 0..HASHES {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) & mask;
+            let flag = 1u64 << (bit % 64);
+            seen &= self.bits[(bit / 64) as usize].fetch_or(flag, Ordering::Relaxed) & flag != 0;
+        }
+        seen
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rec(seq: &str) -> FastqRecord {
+        FastqRecord { name: "@r".into(), seq: seq.into(), plus: "+".into(), qual: "I".repeat(seq.len()) }
+    }
+
+    #[test]
+    fn repeated_sequences_are_duplicates() {
+        let dup = Duplication::with_bits(1 << 16);
+        assert!(!dup.seen(&rec("ACGTACGT"), None));
+        assert!(dup.seen(&rec("ACGTACGT"), None));
+        assert!(!dup.seen(&rec("ACGTACGA"), None));
+        // pairs are keyed by both mates, and the mate boundary counts
+        assert!(!dup.seen(&rec("ACGT"), Some(&rec("ACGT"))));
+        assert!(!dup.seen(&rec("ACG"), Some(&rec("TACGT"))));
+        assert!(dup.seen(&rec("ACGT"), Some(&rec("ACGT"))));
+    }
+
+    #[test]
+    fn distinct_reads_are_rarely_duplicates() {
+        let dup = Duplication::with_bits(1 << 20);
+        let false_duplicates = (0..10_000u32).filter(|i| dup.seen(&rec(&format!("{i:032b}").replace('0', "A").replace('1', "C")), None)).count();
+        assert!(false_duplicates < 10, "{false_duplicates}");
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+use serde::Serialize;
+use crate::fastq::FastqRecord;
+
+pub struct LongReadTrimmer;
+
+impl LongReadTrimmer {
+    /// Quality trimming of long-read ends, like fastplong's cut_front/cut_tail:
+    /// windows of `window` bases are dropped from the head (tail) until one reaches
+    /// the mean quality `head_quality` (`tail_quality`). A quality of 0 disables that end.
+    /// Returns the bases trimmed from the head and from the tail.
+    pub fn trim_ends(rec: &mut FastqRecord, window: usize, head_quality: u8, tail_quality: u8) -> (usize, usize) {
+        let qual = rec.qual.as_bytes();
+        let l = qual.len();
+        let w = window.clamp(1, l.max(1));
+        if l == 0 || (head_quality == 0 && tail_quality == 0) {
+            return (0, 0);
+        }
+        let window_sum = |start: usize| qual[start..start + w].iter().map(|&q| q.saturating_sub(33) as u32).sum::<u32>();
+
+        let mut start = 0;
+        if head_quality > 0 {
+            let need = head_quality as u32 * w as u32;
+            let mut sum = window_sum(0);
+            start = l;
+            for s in 0..=(l - w) {
+                if s > 0 {
+                    sum = sum + qual[s + w - 1].saturating_sub(33) as u32 - qual[s - 1].saturating_sub(33) as u32;
+                }
+                if sum >= need {
+                    start = s;
+                    break;
+                }
+            }
+        }
+
+        let mut end = l;
+        if tail_quality > 0 && start < l {
+            let need = tail_quality as u32 * w as u32;
+            let mut sum = window_sum(l - w);
+            end = start;
+            let mut e = l;
+            while e >= start + w {
+                if e < l {
+                    sum = sum + qual[e - w].saturating_sub(33) as u32 - qual[e].saturating_sub(33) as u32;
+                }
+                if sum >= need {
+                    end = e;
+                    break;
+                }
+                e -= 1;
+            }
+        }
+
+        let end = end.max(start);
+        if start > 0 || end < l {
+            rec.seq = rec.seq[start..end].to_string();
+            rec.qual = rec.qual[start..end].to_string();
+        }
+        (start, l - end)
+    }
+
+    /// Mean quality of a read on the phred scale, averaged over error
+    /// probabilities (as Nanopore tools do) rather than over phred values.
+    pub fn mean_quality(qual: &str) -> f64 {
+        if qual.is_empty() {
+            return 0.0;
+        }
+        let err: f64 = qual.bytes().map(|b| 10f64.powf(-(b.saturating_sub(33) as f64) / 10.0)).sum();
+        -10.0 * (err / qual.len() as f64).log10()
+    }
+}
+
+/// Length distribution of the reads written out, reported in long-read mode.
+#[derive(Default, Serialize, Clone)]
+pub struct LengthStats {
+    pub reads: u64,
+    pub bases: u64,
+    pub min: usize,
+    pub max: usize,
+    pub mean: f64,
+    pub n50: usize,
+    pub n90: usize,
+    /// read-count percentiles: 10, 25, 50 (median), 75, 90
+    pub percentiles: BTreeMap<String, usize>,
+}
+
+impl LengthStats {
+    /// Summarize a histogram of read length -> count.
+    pub fn from_histogram(hist: &BTreeMap<usize, u64>) -> Option<Self> {
+        let reads: u64 = hist.values().sum();
+        if reads == 0 {
+            return None;
+        }
+        let bases: u64 = hist.iter().map(|(&len, &n)| len as u64 * n).sum();
+
+        let mut percentiles = BTreeMap::new();
+        for pct in [10u64, 25, 50, 75, 90] {
+            // smallest length with at least pct% of the reads at or below it
+            let target = (reads * pct).div_ceil(100).max(1);
+            let mut seen = 0;
+            for (&len, &n) in hist {
+                seen += n;
+                if seen >= target {
+                    percentiles.insert(format!("p{}", pct), len);
+                    break;
+                }
+            }
+        }
+
+        // Nx: length of the read at which the longest reads reach x% of all bases
+        let nx = |x: u64| {
+            let mut acc = 0;
+            for (&len, &n) in hist.iter().rev() {
+                acc += len as u64 * n;
+                if acc * 100 >= bases * x {
+                    return len;
+                }
+            }
+            0
+        };
+
+        Some(Self {
+            reads,
+            bases,
+            min: *hist.keys().next().unwrap_or(&0),
+            max: *hist.keys().next_back().unwrap_or(&0),
+            mean: bases as f64 / reads as f64,
+            n50: nx(50),
+            n90: nx(90),
+            percentiles,
+        })
+    }
+}
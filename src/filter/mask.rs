@@ -0,0 +1,56 @@
+use crate::config::Cli;
+use super::pipeline::{TrimState, TrimmedRead};
+use super::processor::Processor;
+use super::Report;
+
+/// The `mask` stage: instead of trimming, bases below `--mask_low_quality` are replaced
+/// with `N` (qualities are kept), and with `--mask_adapter_window` the last bases before an
+/// adapter cut are masked too, where a remnant of the adapter may be left.
+/// Runs before `pass_filters`, so masked bases count against `--n_base_limit`.
+pub struct LowQualityMasker {
+    /// phred threshold, 0 = off
+    quality: u8,
+    adapter_window: usize,
+}
+
+impl LowQualityMasker {
+    pub fn new(c: &Cli) -> Self {
+        Self { quality: c.mask_low_quality, adapter_window: c.mask_adapter_window }
+    }
+
+    fn mask(&self, read: &mut TrimmedRead, report: &mut Report) {
+        let len = read.rec.seq.len();
+        let window_start = if read.adapter_cut { len.saturating_sub(self.adapter_window) } else { len };
+        let min_qual = self.quality.saturating_add(33);
+        let qual = read.rec.qual.as_bytes();
+        let mut seq = std::mem::take(&mut read.rec.seq).into_bytes();
+        for (i, base) in seq.iter_mut().enumerate() {
+            if *base == b'N' || !base.is_ascii() {
+                continue;
+            }
+            if i >= window_start {
+                *base = b'N';
+                report.masked_adapter_bases += 1;
+            } else if self.quality > 0 && qual.get(i).is_some_and(|&q| q < min_qual) {
+                *base = b'N';
+                report.masked_low_quality_bases += 1;
+            }
+        }
+        read.rec.seq = String::from_utf8(seq).expect("masking keeps the sequence ASCII");
+    }
+}
+
+impl Processor for LowQualityMasker {
+    fn name(&self) -> &'static str {
+        "mask"
+    }
+
+    fn process(&self, state: &mut TrimState, report: &mut Report) {
+        if self.quality == 0 && self.adapter_window == 0 {
+            return;
+        }
+        for read in state.reads_mut() {
+            self.mask(read, report);
+        }
+    }
+}
@@ -3,6 +3,20 @@
 pub struct Matcher;
 
 impl Matcher {
+    /// Mismatches between `a` and `b` (same length), or `None` once they exceed `limit`.
+    pub fn diff_within(a: &[u8], b: &[u8], limit: usize) -> Option<usize> {
+        let mut diff = 0;
+        for (x, y) in a.iter().zip(b) {
+            if x != y {
+                diff += 1;
+                if diff > limit {
+                    return None;
+                }
+            }
+        }
+        Some(diff)
+    }
+
     /// Checks if a sequence matches another with exactly one insertion in the first sequence (ins_data).
     /// 
     /// # Arguments
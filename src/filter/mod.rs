@@ -5,16 +5,33 @@ mod overlap;
 mod poly_g;
 mod poly_x;
 mod base_correction;
+mod mismatch;
+mod myers;
+mod long_read;
+mod mott;
+mod pipeline;
+mod processor;
+mod primer;
+mod mask;
+mod read_stats;
+mod duplication;
 
+use std::collections::BTreeMap;
+use std::io;
 use serde::Serialize;
 use crate::fastq::FastqRecord;
 use crate::config::Cli;
-use self::adapter_trimmer::AdapterTrimmer;
-use self::sliding_window::SlidingWindow;
-use self::overlap::OverlapAnalyzer;
-use self::poly_g::PolyGTrimmer;
-use self::poly_x::PolyXTrimmer;
-use self::base_correction::BaseCorrector;
+use self::sliding_window::QualityCutStats;
+use self::adapter_trimmer::AdapterStats;
+use self::base_correction::CorrectionStats;
+use self::long_read::{LengthStats, LongReadTrimmer};
+use self::processor::StageStats;
+use self::duplication::Duplication;
+pub use self::adapter_trimmer::{DEFAULT_BASES_PER_EDIT, DEFAULT_DIMER_MAX_INSERT};
+pub use self::base_correction::DEFAULT_MAX_MERGED_QUAL;
+pub use self::pipeline::{Discard, TrimState, TrimmedRead, DEFAULT_TRIM_ORDER};
+pub use self::processor::{Processor, Registry};
+pub use self::read_stats::ReadStats;
 
 #[derive(Default, Serialize, Clone)]
 pub struct Report {
@@ -26,12 +43,78 @@ pub struct Report {
     pub failed_low_average_qual: u64,
     pub adapter_trimmed_reads: u64,
     pub adapter_trimmed_bases: u64,
+    /// adapter trimming, per mate
+    pub adapter_r1: AdapterStats,
+    pub adapter_r2: AdapterStats,
+    /// reads (pairs) with no insert left between the adapters, and their share of all reads
+    pub adapter_dimer_reads: u64,
+    pub adapter_dimer_percent: f64,
+    /// reads (pairs) whose raw sequence was seen before, and their share of all reads
+    /// (none with `--dont_eval_duplication`)
+    pub duplicated_reads: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duplication_rate: Option<f64>,
+    /// adapter-trimmed reads (pairs) with an insert too short to pass, dimers aside
+    pub short_insert_reads: u64,
     pub poly_g_trimmed_reads: u64,
     pub poly_g_trimmed_bases: u64,
     pub poly_x_trimmed_reads: u64,
     pub poly_x_trimmed_bases: u64,
     pub pe_overlap_avg_diff: f32,
     pub pe_overlap_count: u64,
+    /// bases removed per quality cutting method, per mate
+    pub quality_cut_r1: QualityCutStats,
+    pub quality_cut_r2: QualityCutStats,
+    // overlap correction, per mate
+    pub correction_r1: CorrectionStats,
+    pub correction_r2: CorrectionStats,
+    // long-read mode
+    pub failed_low_mean_qual: u64,
+    pub head_quality_trimmed_bases: u64,
+    pub tail_quality_trimmed_bases: u64,
+    #[serde(skip)]
+    pub read_lengths: BTreeMap<usize, u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_length: Option<LengthStats>,
+    // base masking (bases replaced with N)
+    pub masked_low_quality_bases: u64,
+    pub masked_adapter_bases: u64,
+    // amplicon primers
+    pub failed_no_primer: u64,
+    /// reads (pairs) per amplicon, by recognised primer
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub amplicons: BTreeMap<String, u64>,
+    /// bases and reads trimmed by each stage
+    pub stages: BTreeMap<&'static str, StageStats>,
+    /// reads taken from each input file (read1 files, then read2 files)
+    pub input_files: Vec<InputFileReads>,
+    /// reads as they came in (raw), and as they were written (clean), per mate
+    pub before_r1: ReadStats,
+    #[serde(skip_serializing_if = "ReadStats::is_empty")]
+    pub before_r2: ReadStats,
+    pub after_r1: ReadStats,
+    #[serde(skip_serializing_if = "ReadStats::is_empty")]
+    pub after_r2: ReadStats,
+    /// pairs by insert size (from overlap analysis), and pairs found not to overlap
+    #[serde(skip)]
+    pub insert_sizes: BTreeMap<usize, u64>,
+    #[serde(skip)]
+    pub insert_size_unknown: u64,
+    /// bin table applied to output qualities (`--quality_bins`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality_binning: Option<String>,
+}
+
+#[derive(Default, Serialize, Clone)]
+pub struct InputFileReads {
+    pub path: String,
+    pub reads: u64,
+}
+
+impl From<&(String, u64)> for InputFileReads {
+    fn from((path, reads): &(String, u64)) -> Self {
+        Self { path: path.clone(), reads: *reads }
+    }
 }
 
 impl Report {
@@ -44,10 +127,42 @@ impl Report {
         self.failed_low_average_qual += other.failed_low_average_qual;
         self.adapter_trimmed_reads += other.adapter_trimmed_reads;
         self.adapter_trimmed_bases += other.adapter_trimmed_bases;
+        self.adapter_r1.merge(&other.adapter_r1);
+        self.adapter_r2.merge(&other.adapter_r2);
+        self.adapter_dimer_reads += other.adapter_dimer_reads;
+        self.short_insert_reads += other.short_insert_reads;
+        self.duplicated_reads += other.duplicated_reads;
         self.poly_g_trimmed_reads += other.poly_g_trimmed_reads;
         self.poly_g_trimmed_bases += other.poly_g_trimmed_bases;
         self.poly_x_trimmed_reads += other.poly_x_trimmed_reads;
         self.poly_x_trimmed_bases += other.poly_x_trimmed_bases;
+        self.masked_low_quality_bases += other.masked_low_quality_bases;
+        self.masked_adapter_bases += other.masked_adapter_bases;
+        self.failed_no_primer += other.failed_no_primer;
+        for (amplicon, &n) in &other.amplicons {
+            *self.amplicons.entry(amplicon.clone()).or_insert(0) += n;
+        }
+        for (name, stats) in &other.stages {
+            self.stages.entry(name).or_default().merge(stats);
+        }
+        self.quality_cut_r1.merge(&other.quality_cut_r1);
+        self.quality_cut_r2.merge(&other.quality_cut_r2);
+        self.correction_r1.merge(&other.correction_r1);
+        self.correction_r2.merge(&other.correction_r2);
+        self.failed_low_mean_qual += other.failed_low_mean_qual;
+        self.head_quality_trimmed_bases += other.head_quality_trimmed_bases;
+        self.tail_quality_trimmed_bases += other.tail_quality_trimmed_bases;
+        self.before_r1.merge(&other.before_r1);
+        self.before_r2.merge(&other.before_r2);
+        self.after_r1.merge(&other.after_r1);
+        self.after_r2.merge(&other.after_r2);
+        for (&size, &n) in &other.insert_sizes {
+            *self.insert_sizes.entry(size).or_insert(0) += n;
+        }
+        self.insert_size_unknown += other.insert_size_unknown;
+        for (&len, &n) in &other.read_lengths {
+            *self.read_lengths.entry(len).or_insert(0) += n;
+        }
         // weighted average for overlap diff
         let total = self.pe_overlap_count + other.pe_overlap_count;
         if total > 0 {
@@ -57,122 +172,90 @@ impl Report {
             self.pe_overlap_count = total;
         }
     }
+
+    /// Count a written read in the length distribution (long-read mode).
+    pub fn add_read_length(&mut self, len: usize) {
+        *self.read_lengths.entry(len).or_insert(0) += 1;
+    }
+
+    /// Derive the summary fields (rates, length percentiles, N50, top adapters, dimer share) once all packs are merged.
+    pub fn finalize(&mut self) {
+        if self.total_reads > 0 {
+            self.adapter_dimer_percent = self.adapter_dimer_reads as f64 * 100.0 / self.total_reads as f64;
+        }
+        for stats in [&mut self.before_r1, &mut self.before_r2, &mut self.after_r1, &mut self.after_r2] {
+            stats.finalize();
+        }
+        self.adapter_r1.finalize();
+        self.adapter_r2.finalize();
+        self.read_length = LengthStats::from_histogram(&self.read_lengths);
+    }
 }
 
 pub struct Filter {
     config: Cli,
+    /// trimming stages in the order they run
+    stages: Vec<Box<dyn Processor>>,
+    /// raw sequences seen so far (off with `--dont_eval_duplication`)
+    duplication: Option<Duplication>,
 }
 
 impl Filter {
-    pub fn new(config: Cli) -> Self {
-        Self { config }
+    /// Filter with the stages of `registry` in `--trim_order`.
+    pub fn new(config: Cli, registry: &Registry) -> io::Result<Self> {
+        let stages = registry.build(&config.trim_order, &config)?;
+        let duplication = (!config.dont_eval_duplication).then(Duplication::new);
+        Ok(Self { config, stages, duplication })
     }
 
-    pub fn trim_pair(&self, mut r1: FastqRecord, mut r2: FastqRecord, report: &mut Report) -> (FastqRecord, FastqRecord) {
-        let res = OverlapAnalyzer::analyze_with_params(&r1, &r2, self.config.overlap_len_require, self.config.overlap_diff_limit, (self.config.overlap_diff_percent_limit as f32) / 100.0);
-        if res.overlapped {
-            // accumulate average diff
-            let prev = report.pe_overlap_avg_diff * report.pe_overlap_count as f32;
-            report.pe_overlap_count += 1;
-            report.pe_overlap_avg_diff = (prev + res.diff as f32) / report.pe_overlap_count as f32;
-        }
-        if self.config.correction && res.overlapped && res.overlap_len >= self.config.overlap_len_require {
-            BaseCorrector::correct(&mut r1, &mut r2, res.offset, res.overlap_len);
-        }
-        if !self.config.disable_adapter_trimming 
-           && self.config.adapter_sequence.is_none() 
-           && self.config.adapter_sequence_r2.is_none() {
-            if res.overlapped {
-                let offset = res.offset;
-                let overlap_len = res.overlap_len;
-                if offset >= 0 {
-                    let off = offset as usize;
-                    if r1.seq.len() > off + overlap_len {
-                        let trimmed_len = r1.seq.len() - (off + overlap_len);
-                        report.adapter_trimmed_bases += trimmed_len as u64;
-                        r1.seq.truncate(off + overlap_len);
-                        r1.qual.truncate(off + overlap_len);
-                        report.adapter_trimmed_reads += 1;
-                    }
-                } else {
-                    let k = (-offset) as usize;
-                    if r1.seq.len() > overlap_len {
-                        let trimmed_len = r1.seq.len() - overlap_len;
-                        report.adapter_trimmed_bases += trimmed_len as u64;
-                        r1.seq.truncate(overlap_len);
-                        r1.qual.truncate(overlap_len);
-                        report.adapter_trimmed_reads += 1;
-                    }
-                    if r2.seq.len() > k {
-                        let new_len = r2.seq.len().saturating_sub(k);
-                        let trimmed_len = r2.seq.len() - new_len;
-                        if trimmed_len > 0 {
-                            report.adapter_trimmed_bases += trimmed_len as u64;
-                            r2.seq.truncate(new_len);
-                            r2.qual.truncate(new_len);
-                            report.adapter_trimmed_reads += 1;
-                        }
-                    }
-                }
-            }
+    /// Count a raw read (pair) as a duplicate when its sequence was seen before.
+    pub fn count_duplicate(&self, r1: &FastqRecord, r2: Option<&FastqRecord>, report: &mut Report) {
+        if self.duplication.as_ref().is_some_and(|d| d.seen(r1, r2)) {
+            report.duplicated_reads += 1;
         }
-        
-        // Continue with individual record trimming (Quality, etc.)
-        let r1 = self.trim_record(r1, false, report);
-        let r2 = self.trim_record(r2, true, report);
-        
-        (r1, r2)
     }
 
-    pub fn trim_record(&self, mut rec: FastqRecord, is_r2: bool, report: &mut Report) -> FastqRecord {
-        let front = if is_r2 { self.config.trim_front2 } else { self.config.trim_front1 };
-        let tail = if is_r2 { self.config.trim_tail2 } else { self.config.trim_tail1 };
-        let max_len = if is_r2 { self.config.max_len2 } else { self.config.max_len1 };
-
-        // Adapter Trimming
-        if !self.config.disable_adapter_trimming {
-            let adapter_seq = if is_r2 {
-                self.config.adapter_sequence_r2.as_deref()
-            } else {
-                self.config.adapter_sequence.as_deref()
-            };
-            
-            // If explicit adapter sequence is provided, use it
-            if let Some(_) = adapter_seq {
-                AdapterTrimmer::trim_by_sequence(&mut rec, adapter_seq, report, is_r2);
-            }
-        }
-        
-        if self.config.trim_poly_x {
-            PolyXTrimmer::trim_poly_x(&mut rec, self.config.poly_x_min_len, report);
-        }
-        if self.config.trim_poly_g && !self.config.disable_trim_poly_g {
-            PolyGTrimmer::trim_poly_g(&mut rec, self.config.poly_g_min_len, report);
-        }
+    pub fn long_read(&self) -> bool {
+        self.config.long_read
+    }
 
-        // Sliding Window Quality Cutting
-        let mut front_trimmed_count = 0;
-        SlidingWindow::trim_and_cut(&mut rec, &self.config, &mut front_trimmed_count);
+    /// Trim a pair, or say why a stage discarded it.
+    pub fn trim_pair(&self, r1: FastqRecord, r2: FastqRecord, report: &mut Report) -> Result<(FastqRecord, FastqRecord), Discard> {
+        let mut state = TrimState::new(r1, Some(r2));
+        self.run(&mut state, report);
+        if let Some(discard) = state.discard() {
+            return Err(discard);
+        }
+        let TrimState { r1, r2, .. } = state;
+        Ok((r1.rec, r2.expect("a pair keeps both mates").rec))
+    }
 
-        let start = front.min(rec.seq.len());
-        let mut end = rec.seq.len().saturating_sub(tail);
-        
-        if max_len > 0 {
-            let max_end = start.saturating_add(max_len);
-            end = end.min(max_end);
+    /// Trim a single-end read, or say why a stage discarded it.
+    pub fn trim_record(&self, rec: FastqRecord, report: &mut Report) -> Result<FastqRecord, Discard> {
+        let mut state = TrimState::new(rec, None);
+        self.run(&mut state, report);
+        match state.discard() {
+            Some(discard) => Err(discard),
+            None => Ok(state.r1.rec),
         }
-        
-        if start >= end { 
-            rec.seq.clear(); 
-            rec.qual.clear(); 
-            return rec; 
+    }
+
+    /// Run the stages in `--trim_order`, tallying what each one trims.
+    fn run(&self, state: &mut TrimState, report: &mut Report) {
+        for stage in &self.stages {
+            let before = state.lengths();
+            stage.process(state, report);
+            if state.discarded {
+                return;
+            }
+            let after = state.lengths();
+            let trimmed = before.iter().zip(&after).filter(|(b, a)| a < b).count();
+            if trimmed > 0 {
+                let stats = report.stages.entry(stage.name()).or_default();
+                stats.trimmed_reads += trimmed as u64;
+                stats.trimmed_bases += before.iter().zip(&after).map(|(b, a)| b.saturating_sub(*a) as u64).sum::<u64>();
+            }
         }
-        
-        let seq = rec.seq[start..end].to_string();
-        let qual = rec.qual[start..end].to_string();
-        rec.seq = seq;
-        rec.qual = qual;
-        rec
     }
 
     pub fn pass_filters(&self, rec: &FastqRecord, rep: &mut Report) -> bool {
@@ -197,6 +280,11 @@ impl Filter {
             rep.failed_low_average_qual += 1; 
             return false; 
         }
+
+        if self.config.long_read && self.config.mean_qual > 0 && LongReadTrimmer::mean_quality(&rec.qual) < self.config.mean_qual as f64 {
+            rep.failed_low_mean_qual += 1;
+            return false;
+        }
         
         let mut low = 0usize;
         for b in rec.qual.bytes() { 
@@ -0,0 +1,44 @@
+use crate::fastq::FastqRecord;
+
+pub struct MottTrimmer;
+
+impl MottTrimmer {
+    /// Running-sum quality trimming as in BWA's `-q` (a variant of the Mott algorithm):
+    /// walking in from an end, `quality - q` is summed over the bases and the read is cut
+    /// where that sum peaks; the walk stops as soon as the sum drops below zero.
+    /// The 3' end is trimmed first, then the 5' end the same way.
+    /// Returns the bases trimmed from the front and from the tail.
+    pub fn trim(rec: &mut FastqRecord, quality: u8) -> (usize, usize) {
+        let qual = rec.qual.as_bytes();
+        let l = qual.len();
+        let score = |i: usize| quality as i32 - qual[i].saturating_sub(33) as i32;
+
+        let mut end = l;
+        let (mut sum, mut best) = (0, 0);
+        for i in (0..l).rev() {
+            sum += score(i);
+            if sum < 0 { break; }
+            if sum > best {
+                best = sum;
+                end = i;
+            }
+        }
+
+        let mut start = 0;
+        let (mut sum, mut best) = (0, 0);
+        for i in 0..end {
+            sum += score(i);
+            if sum < 0 { break; }
+            if sum > best {
+                best = sum;
+                start = i + 1;
+            }
+        }
+
+        if start > 0 || end < l {
+            rec.seq = rec.seq[start..end].to_string();
+            rec.qual = rec.qual[start..end].to_string();
+        }
+        (start, l - end)
+    }
+}
@@ -0,0 +1,127 @@
+//! Bit-parallel approximate matching (Myers 1999, in Hyyrö's formulation): the edit distance
+//! of a pattern of up to 64 bases against every end position of a text, one word operation
+//! per text base.
+
+/// Longest pattern that fits the bit vectors; longer patterns are cut to this length.
+pub const MAX_PATTERN_LEN: usize = 64;
+
+pub struct Myers {
+    /// per byte, the pattern positions holding it
+    peq: [u64; 256],
+    len: usize,
+}
+
+/// Vertical score deltas of the last column of a scan.
+pub struct Column {
+    pv: u64,
+    mv: u64,
+}
+
+impl Column {
+    /// Edit distance of the first `i` pattern bases against the text ending at the last position.
+    pub fn prefix_score(&self, i: usize) -> usize {
+        let mask = if i >= 64 { u64::MAX } else { (1u64 << i) - 1 };
+        ((self.pv & mask).count_ones() - (self.mv & mask).count_ones()) as usize
+    }
+}
+
+impl Myers {
+    pub fn new(pattern: &[u8]) -> Self {
+        let pattern = &pattern[..pattern.len().min(MAX_PATTERN_LEN)];
+        let mut peq = [0u64; 256];
+        for (i, &b) in pattern.iter().enumerate() {
+            peq[b as usize] |= 1 << i;
+        }
+        Self { peq, len: pattern.len() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Scan `text`, calling `on_column(j, score)` with the edit distance of the whole pattern
+    /// against the best text substring ending at `j`. With `free_prefix`, the alignment may
+    /// also skip a prefix of the pattern for free at the start of the text (the pattern
+    /// hangs off the text start). Bytes match only themselves, so an N is a mismatch
+    /// against any other base.
+    pub fn scan(&self, text: impl Iterator<Item = u8>, free_prefix: bool, mut on_column: impl FnMut(usize, usize)) -> Column {
+        if self.len == 0 {
+            return Column { pv: 0, mv: 0 };
+        }
+        let high = 1u64 << (self.len - 1);
+        let (mut pv, mut mv, mut score) = if free_prefix { (0u64, 0u64, 0usize) } else { (u64::MAX, 0u64, self.len) };
+        for (j, b) in text.enumerate() {
+            let eq = self.peq[b as usize];
+            let xv = eq | mv;
+            let xh = ((eq & pv).wrapping_add(pv) ^ pv) | eq;
+            let ph = mv | !(xh | pv);
+            let mh = pv & xh;
+            if ph & high != 0 {
+                score += 1;
+            } else if mh & high != 0 {
+                score -= 1;
+            }
+            // the text start is free: no carry into the first row
+            let ph = ph << 1;
+            let mh = mh << 1;
+            pv = mh | !(xv | ph);
+            mv = ph & xv;
+            on_column(j, score);
+        }
+        Column { pv, mv }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// Plain DP of `Myers::scan`: the score of each text position, and the last column.
+    fn edit_distance_table(pattern: &[u8], text: &[u8], free_prefix: bool) -> (Vec<usize>, Vec<usize>) {
+        let mut column: Vec<usize> = (0..=pattern.len()).map(|i| if free_prefix { 0 } else { i }).collect();
+        let mut scores = Vec::with_capacity(text.len());
+        for &b in text {
+            let mut next = vec![0; pattern.len() + 1];
+            for i in 1..=pattern.len() {
+                let substitution = column[i - 1] + (pattern[i - 1] != b) as usize;
+                next[i] = substitution.min(column[i] + 1).min(next[i - 1] + 1);
+            }
+            scores.push(next[pattern.len()]);
+            column = next;
+        }
+        (scores, column)
+    }
+
+    #[test]
+    fn scores_match_the_edit_distance_table() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let random = |rng: &mut StdRng, len: usize| -> Vec<u8> { (0..len).map(|_| b"ACGTN"[rng.random_range(0..5)]).collect() };
+        for _ in 0..5000 {
+            let len = 1 + rng.random_range(0..MAX_PATTERN_LEN);
+            let pattern = random(&mut rng, len);
+            let len = rng.random_range(0..=160);
+            let text = random(&mut rng, len);
+            for free_prefix in [false, true] {
+                let mut scores = Vec::new();
+                let end = Myers::new(&pattern).scan(text.iter().copied(), free_prefix, |_, score| scores.push(score));
+                let (plain_scores, plain_end) = edit_distance_table(&pattern, &text, free_prefix);
+                assert_eq!(scores, plain_scores);
+                if !text.is_empty() {
+                    assert_eq!((0..=pattern.len()).map(|i| end.prefix_score(i)).collect::<Vec<_>>(), plain_end);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn exact_and_one_edit_matches() {
+        let myers = Myers::new(b"ACGT");
+        let mut scores = Vec::new();
+        myers.scan(b"TTACGTTACTT".iter().copied(), false, |_, score| scores.push(score));
+        assert_eq!(scores[5], 0);
+        assert_eq!(scores[9], 1);
+        assert_eq!(scores.iter().min(), Some(&0));
+    }
+}
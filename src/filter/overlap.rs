@@ -1,103 +1,443 @@
+use std::cell::RefCell;
+use std::ops::Range;
+use crate::config::Cli;
 use crate::fastq::FastqRecord;
+use super::base_correction::BaseCorrector;
+use super::mismatch::count_diff;
+use super::pipeline::TrimState;
+use super::processor::Processor;
+use super::Report;
 
+/// Overlap of R1 with the reverse complement of R2 (`S2`): R1 position `offset + i` faces
+/// S2 position `i`, so a negative offset means S2 starts before R1 (the insert is shorter than R2).
+#[derive(Clone, Debug, PartialEq)]
 pub struct OverlapResult {
     pub overlapped: bool,
     pub offset: i32,
     pub overlap_len: usize,
     pub diff: usize,
+    /// fragment length, from the 5' end of R1 to the 5' end of R2 (0 when not overlapped)
+    pub insert_size: usize,
+    /// the overlapping bases in R1, and in R2 in its own orientation
+    pub r1_range: Range<usize>,
+    pub r2_range: Range<usize>,
+    /// the insert is shorter than a read, which then reads on into the adapter
+    pub adapter_readthrough: bool,
+}
+
+impl OverlapResult {
+    fn none() -> Self {
+        Self { overlapped: false, offset: 0, overlap_len: 0, diff: usize::MAX, insert_size: 0, r1_range: 0..0, r2_range: 0..0, adapter_readthrough: false }
+    }
+
+    fn found(offset: i32, overlap_len: usize, diff: usize, len1: usize, len2: usize) -> Self {
+        let (start1, start_s2) = if offset >= 0 { (offset as usize, 0) } else { (0, (-offset) as usize) };
+        let insert_size = (offset + len2 as i32) as usize;
+        Self {
+            overlapped: true,
+            offset,
+            overlap_len,
+            diff,
+            insert_size,
+            r1_range: start1..start1 + overlap_len,
+            r2_range: len2 - start_s2 - overlap_len..len2 - start_s2,
+            adapter_readthrough: insert_size < len1 || insert_size < len2,
+        }
+    }
 }
 
 pub struct OverlapAnalyzer;
 
+/// How the overlap offset is chosen.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OverlapSearch {
+    /// upstream fastp: the first offset within the limits, from the longest overlap down
+    Upstream,
+    /// the offset with the fewest mismatches (`--overlap_best_match`)
+    BestMatch,
+}
+
+/// Overlap parameters from the command line.
+#[derive(Clone, Copy)]
+pub struct OverlapParams {
+    pub min_overlap: usize,
+    pub diff_limit: usize,
+    pub diff_percent_limit: f32,
+    pub search: OverlapSearch,
+}
+
+impl OverlapParams {
+    pub fn new(c: &Cli) -> Self {
+        Self {
+            min_overlap: c.overlap_len_require,
+            diff_limit: c.overlap_diff_limit,
+            diff_percent_limit: (c.overlap_diff_percent_limit as f32) / 100.0,
+            search: if c.overlap_best_match { OverlapSearch::BestMatch } else { OverlapSearch::Upstream },
+        }
+    }
+
+    pub fn analyze(&self, r1: &FastqRecord, r2: &FastqRecord) -> OverlapResult {
+        match self.search {
+            OverlapSearch::Upstream => OverlapAnalyzer::analyze_upstream(r1, r2, self.min_overlap, self.diff_limit, self.diff_percent_limit),
+            OverlapSearch::BestMatch => OverlapAnalyzer::analyze_with_params(r1, r2, self.min_overlap, self.diff_limit, self.diff_percent_limit),
+        }
+    }
+}
+
+/// The `overlap` stage (PE): overlap statistics, then base correction with `--correction`.
+/// The result is kept in `TrimState::overlap` for the adapter stage.
+pub struct OverlapCorrector {
+    params: OverlapParams,
+    correction: bool,
+    /// cap of merged qualities of agreeing bases, 0 = keep the qualities
+    max_qual: u8,
+}
+
+impl OverlapCorrector {
+    pub fn new(c: &Cli) -> Self {
+        Self { params: OverlapParams::new(c), correction: c.correction, max_qual: c.correction_max_qual }
+    }
+}
+
+impl Processor for OverlapCorrector {
+    fn name(&self) -> &'static str {
+        "overlap"
+    }
+
+    fn process(&self, state: &mut TrimState, report: &mut Report) {
+        let Some(r2) = &mut state.r2 else { return };
+        let r1 = &mut state.r1;
+        let res = self.params.analyze(&r1.rec, &r2.rec);
+        if res.overlapped {
+            // accumulate average diff
+            let prev = report.pe_overlap_avg_diff * report.pe_overlap_count as f32;
+            report.pe_overlap_count += 1;
+            report.pe_overlap_avg_diff = (prev + res.diff as f32) / report.pe_overlap_count as f32;
+            // in original coordinates: bases already cut from the 5' ends were part of the fragment
+            let insert_size = res.insert_size + r1.front_offset + r2.front_offset;
+            *report.insert_sizes.entry(insert_size).or_insert(0) += 1;
+        } else {
+            report.insert_size_unknown += 1;
+        }
+        if self.correction && res.overlapped && res.overlap_len >= self.params.min_overlap {
+            let mut stats = [std::mem::take(&mut report.correction_r1), std::mem::take(&mut report.correction_r2)];
+            BaseCorrector::correct(&mut r1.rec, &mut r2.rec, res.offset, res.overlap_len, self.max_qual, &mut stats);
+            [report.correction_r1, report.correction_r2] = stats;
+        }
+        state.overlap = Some(res);
+    }
+}
+
+/// Length of the k-mer seeds; a seed is read as one `u64`, so every byte (N too) is a symbol.
+const SEED_LEN: usize = 8;
+
+/// Upstream compares at least this many bases before giving up on an offset; an offset whose
+/// first 50 bases are within the diff limit is taken, whatever the rest holds.
+const COMPLETE_COMPARE_REQUIRE: usize = 50;
+
+/// Per-thread buffers, so analyzing a pair does not allocate.
+#[derive(Default)]
+struct Scratch {
+    r2_rc: Vec<u8>,
+    seeds: Vec<(u64, u32)>,
+    /// offsets where some seed matches, indexed by `offset + len2`
+    candidates: Vec<bool>,
+}
+
+thread_local! {
+    static SCRATCH: RefCell<Scratch> = RefCell::new(Scratch::default());
+}
+
+/// Mismatches allowed in an overlap of `overlap_len` bases.
+fn diff_limit_for(overlap_len: usize, diff_limit: usize, diff_percent_limit: f32) -> usize {
+    std::cmp::min(diff_limit, (overlap_len as f32 * diff_percent_limit) as usize)
+}
+
 impl OverlapAnalyzer {
+    #[allow(dead_code)]
     pub fn analyze(r1: &FastqRecord, r2: &FastqRecord) -> OverlapResult {
         Self::analyze_with_params(r1, r2, 10, 5, 0.2)
     }
 
+    /// Overlap as upstream fastp finds it: offsets are tried from 0 upwards (R1 leading),
+    /// then from 0 downwards (R2 leading, adapter read-through), each only while more than
+    /// `min_overlap` bases of the leading read remain, and the first one whose first
+    /// `COMPLETE_COMPARE_REQUIRE` bases are within the diff limit is taken.
+    ///
+    /// Offsets are skipped by seeds as in `analyze_with_params`, over that compared head.
+    pub fn analyze_upstream(r1: &FastqRecord, r2: &FastqRecord, min_overlap: usize, diff_limit: usize, diff_percent_limit: f32) -> OverlapResult {
+        SCRATCH.with(|scratch| {
+            let scratch = &mut *scratch.borrow_mut();
+            let seq1 = r1.seq.as_bytes();
+            let (len1, len2) = (seq1.len(), r2.seq.len());
+            reverse_complement_into(r2.seq.as_bytes(), &mut scratch.r2_rc);
+            mark_seed_offsets(seq1, scratch);
+            let s2 = &scratch.r2_rc[..];
+            let candidates = &scratch.candidates;
+
+            let try_offset = |offset: i32, a: &[u8], b: &[u8]| -> Option<OverlapResult> {
+                let overlap_len = a.len();
+                if overlap_len == 0 { return None; }
+                let limit = diff_limit_for(overlap_len, diff_limit, diff_percent_limit);
+                let head = overlap_len.min(COMPLETE_COMPARE_REQUIRE);
+                if head / SEED_LEN > limit && !candidates[(offset + len2 as i32) as usize] { return None; }
+                let head_diff = count_diff(&a[..head], &b[..head], limit);
+                if head_diff > limit { return None; }
+                let diff = head_diff + count_diff(&a[head..], &b[head..], usize::MAX);
+                Some(OverlapResult::found(offset, overlap_len, diff, len1, len2))
+            };
+
+            for offset in 0..len1.saturating_sub(min_overlap) {
+                let overlap_len = std::cmp::min(len1 - offset, len2);
+                if let Some(res) = try_offset(offset as i32, &seq1[offset..offset + overlap_len], &s2[..overlap_len]) {
+                    return res;
+                }
+            }
+            for offset_pos in 0..len2.saturating_sub(min_overlap) {
+                let overlap_len = std::cmp::min(len2 - offset_pos, len1);
+                if let Some(res) = try_offset(-(offset_pos as i32), &seq1[..overlap_len], &s2[offset_pos..offset_pos + overlap_len]) {
+                    return res;
+                }
+            }
+            OverlapResult::none()
+        })
+    }
+
+    /// Best overlap of R1 with reverse-complemented R2: the offset with the fewest
+    /// mismatches (then the longest overlap) among those within the diff limits.
+    ///
+    /// An overlap of length `L` with at most `limit` mismatches has an exact seed match in
+    /// one of its `L / SEED_LEN` disjoint seeds whenever `L / SEED_LEN > limit`, so such
+    /// offsets are only compared when a seed of R1 matches one of R2 there; the shorter
+    /// ones are always compared. The result is the same as trying every offset.
     pub fn analyze_with_params(r1: &FastqRecord, r2: &FastqRecord, min_overlap: usize, diff_limit: usize, diff_percent_limit: f32) -> OverlapResult {
+        SCRATCH.with(|scratch| {
+            let scratch = &mut *scratch.borrow_mut();
+            let seq1 = r1.seq.as_bytes();
+            let (len1, len2) = (seq1.len(), r2.seq.len());
+            reverse_complement_into(r2.seq.as_bytes(), &mut scratch.r2_rc);
+            mark_seed_offsets(seq1, scratch);
+            let s2 = &scratch.r2_rc[..];
+            let candidates = &scratch.candidates;
+
+            let mut best = OverlapResult::none();
+            let mut try_offset = |offset: i32, a: &[u8], b: &[u8]| {
+                let overlap_len = a.len();
+                if overlap_len < min_overlap { return; }
+                let limit = diff_limit_for(overlap_len, diff_limit, diff_percent_limit);
+                if overlap_len / SEED_LEN > limit && !candidates[(offset + len2 as i32) as usize] { return; }
+                let diff = count_diff(a, b, limit.min(best.diff));
+                if diff <= limit && (diff < best.diff || (diff == best.diff && overlap_len > best.overlap_len)) {
+                    best = OverlapResult::found(offset, overlap_len, diff, len1, len2);
+                }
+            };
+
+            // Direction 1: R1 starts before S2 (offset >= 0)
+            for offset in 0..len1 {
+                let overlap_len = std::cmp::min(len1 - offset, len2);
+                try_offset(offset as i32, &seq1[offset..offset + overlap_len], &s2[..overlap_len]);
+            }
+            // Direction 2: S2 starts before R1 (offset < 0)
+            for offset_pos in 1..len2 {
+                let overlap_len = std::cmp::min(len2 - offset_pos, len1);
+                try_offset(-(offset_pos as i32), &s2[offset_pos..offset_pos + overlap_len], &seq1[..overlap_len]);
+            }
+            best
+        })
+    }
+}
+
+/// Mark in `scratch.candidates` every offset at which a seed of `seq1` equals one of `scratch.r2_rc`.
+fn mark_seed_offsets(seq1: &[u8], scratch: &mut Scratch) {
+    let Scratch { r2_rc, seeds, candidates } = scratch;
+    let len2 = r2_rc.len();
+    candidates.clear();
+    candidates.resize(seq1.len() + len2, false);
+    seeds.clear();
+    seeds.extend(r2_rc.windows(SEED_LEN).enumerate().map(|(q, w)| (seed_key(w), q as u32)));
+    seeds.sort_unstable();
+    for (p, w) in seq1.windows(SEED_LEN).enumerate() {
+        let key = seed_key(w);
+        let start = seeds.partition_point(|&(k, _)| k < key);
+        for &(_, q) in seeds[start..].iter().take_while(|&&(k, _)| k == key) {
+            candidates[p + len2 - q as usize] = true;
+        }
+    }
+}
+
+fn seed_key(window: &[u8]) -> u64 {
+    u64::from_le_bytes(window.try_into().expect("seed windows are SEED_LEN bytes"))
+}
+
+fn reverse_complement_into(seq: &[u8], out: &mut Vec<u8>) {
+    out.clear();
+    out.extend(seq.iter().rev().map(|b| match b {
+        b'A' => b'T',
+        b'T' => b'A',
+        b'C' => b'G',
+        b'G' => b'C',
+        _ => b'N',
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use clap::Parser;
+    use crate::filter::mismatch::count_diff_scalar;
+
+    /// Upstream's loop as written, offset by offset.
+    fn analyze_upstream_exhaustive(r1: &FastqRecord, r2: &FastqRecord, min_overlap: usize, diff_limit: usize, diff_percent_limit: f32) -> OverlapResult {
+        let seq1 = r1.seq.as_bytes();
+        let mut s2 = Vec::new();
+        reverse_complement_into(r2.seq.as_bytes(), &mut s2);
+        let (len1, len2) = (seq1.len() as i64, s2.len() as i64);
+        let compare = |offset: i64| -> Option<OverlapResult> {
+            let (start1, start2) = if offset >= 0 { (offset, 0) } else { (0, -offset) };
+            let overlap_len = std::cmp::min(len1 - start1, len2 - start2);
+            let limit = diff_limit_for(overlap_len as usize, diff_limit, diff_percent_limit);
+            let (mut diff, mut i) = (0, 0);
+            while i < overlap_len {
+                if seq1[(start1 + i) as usize] != s2[(start2 + i) as usize] {
+                    diff += 1;
+                    if diff > limit && i < COMPLETE_COMPARE_REQUIRE as i64 { break; }
+                }
+                i += 1;
+            }
+            let accepted = diff <= limit || i > COMPLETE_COMPARE_REQUIRE as i64;
+            (overlap_len > 0 && accepted).then(|| OverlapResult::found(offset as i32, overlap_len as usize, diff, len1 as usize, len2 as usize))
+        };
+        let mut offset = 0;
+        while offset < len1 - min_overlap as i64 {
+            if let Some(res) = compare(offset) { return res; }
+            offset += 1;
+        }
+        offset = 0;
+        while offset > -(len2 - min_overlap as i64) {
+            if let Some(res) = compare(offset) { return res; }
+            offset -= 1;
+        }
+        OverlapResult::none()
+    }
+
+    /// The plain search trying every offset.
+    fn analyze_exhaustive(r1: &FastqRecord, r2: &FastqRecord, min_overlap: usize, diff_limit: usize, diff_percent_limit: f32) -> OverlapResult {
         let len1 = r1.seq.len();
         let len2 = r2.seq.len();
         let seq1 = r1.seq.as_bytes();
-        let r2_rc = reverse_complement(r2.seq.as_bytes());
-        let s2 = r2_rc.as_bytes();
-
-        let mut best_offset = 0;
-        let mut best_diff = usize::MAX;
-        let mut best_overlap_len = 0;
-        let mut found = false;
+        let mut s2 = Vec::new();
+        reverse_complement_into(r2.seq.as_bytes(), &mut s2);
 
-        // Direction 1: R1 starts before S2 (offset >= 0)
-        // R1: [offset] matches S2: [0]
+        let mut best = OverlapResult::none();
+        let mut try_offset = |offset: i32, a: &[u8], b: &[u8]| {
+            let overlap_len = a.len();
+            if overlap_len < min_overlap { return; }
+            let diff = count_diff_scalar(a, b, usize::MAX);
+            let limit = diff_limit_for(overlap_len, diff_limit, diff_percent_limit);
+            if diff <= limit && (diff < best.diff || (diff == best.diff && overlap_len > best.overlap_len)) {
+                best = OverlapResult::found(offset, overlap_len, diff, len1, len2);
+            }
+        };
         for offset in 0..len1 {
-             let overlap_len = std::cmp::min(len1 - offset, len2);
-             if overlap_len < min_overlap { continue; }
-             
-             let diff = count_diff(&seq1[offset..], &s2[0..overlap_len], overlap_len);
-             let limit = std::cmp::min(diff_limit, (overlap_len as f32 * diff_percent_limit) as usize);
-             
-             if diff <= limit {
-                 // Prefer larger overlap length? Or smaller diff ratio?
-                 // Here we simply check if it's better than current best
-                 if diff < best_diff || (diff == best_diff && overlap_len > best_overlap_len) {
-                     best_diff = diff;
-                     best_offset = offset as i32;
-                     best_overlap_len = overlap_len;
-                     found = true;
-                 }
-             }
+            let overlap_len = std::cmp::min(len1 - offset, len2);
+            try_offset(offset as i32, &seq1[offset..offset + overlap_len], &s2[..overlap_len]);
         }
-
-        // Direction 2: S2 starts before R1 (offset < 0)
-        // S2: [offset_pos] matches R1: [0]
-        // offset = -offset_pos
         for offset_pos in 1..len2 {
             let overlap_len = std::cmp::min(len2 - offset_pos, len1);
-            if overlap_len < min_overlap { continue; }
-
-            let diff = count_diff(&s2[offset_pos..], &seq1[0..overlap_len], overlap_len);
-            let limit = std::cmp::min(diff_limit, (overlap_len as f32 * diff_percent_limit) as usize);
-
-            if diff <= limit {
-                 if diff < best_diff || (diff == best_diff && overlap_len > best_overlap_len) {
-                     best_diff = diff;
-                     best_offset = -(offset_pos as i32);
-                     best_overlap_len = overlap_len;
-                     found = true;
-                 }
-            }
+            try_offset(-(offset_pos as i32), &s2[offset_pos..offset_pos + overlap_len], &seq1[..overlap_len]);
         }
+        best
+    }
+
+    fn record(seq: &str) -> FastqRecord {
+        FastqRecord::new("@r".to_string(), seq.to_string(), "+".to_string(), "I".repeat(seq.len()))
+    }
+
+    fn random_seq(rng: &mut StdRng, len: usize) -> String {
+        (0..len).map(|_| b"ACGTN"[if rng.random_bool(0.01) { 4 } else { rng.random_range(0..4) }] as char).collect()
+    }
+
+    /// A pair read from both ends of a random fragment with sequencing errors; one in ten is unrelated.
+    /// Lengths run from 0, so some reads are shorter than a seed.
+    fn random_pair(rng: &mut StdRng) -> (FastqRecord, FastqRecord) {
+        let fragment_len = rng.random_range(0..320);
+        let fragment = random_seq(rng, fragment_len);
+        let (len1, len2) = (rng.random_range(0..=151), rng.random_range(0..=151));
+        let error_rate = rng.random_range(0.0..0.08);
+        let read = |rng: &mut StdRng, template: &[u8], len: usize| -> String {
+            let tail = random_seq(rng, len.saturating_sub(template.len()));
+            template.iter().take(len).map(|&b| if rng.random_bool(error_rate) { b"ACGTN"[rng.random_range(0..5)] } else { b })
+                .map(char::from).chain(tail.chars()).collect()
+        };
+        let mut fragment_rc = Vec::new();
+        reverse_complement_into(fragment.as_bytes(), &mut fragment_rc);
+        let seq1 = read(rng, fragment.as_bytes(), len1);
+        let seq2 = if rng.random_bool(0.1) { random_seq(rng, len2) } else { read(rng, &fragment_rc, len2) };
+        (record(&seq1), record(&seq2))
+    }
 
-        OverlapResult {
-            overlapped: found,
-            offset: best_offset,
-            overlap_len: best_overlap_len,
-            diff: best_diff,
+    #[test]
+    fn upstream_search_matches_its_loop() {
+        let mut rng = StdRng::seed_from_u64(11);
+        for _ in 0..5_000 {
+            let (r1, r2) = random_pair(&mut rng);
+            let (min_overlap, diff_limit) = (rng.random_range(0..=40), rng.random_range(0..=10));
+            let diff_percent_limit = rng.random_range(0..=30) as f32 / 100.0;
+            assert_eq!(
+                OverlapAnalyzer::analyze_upstream(&r1, &r2, min_overlap, diff_limit, diff_percent_limit),
+                analyze_upstream_exhaustive(&r1, &r2, min_overlap, diff_limit, diff_percent_limit),
+                "{} {} (min {}, limit {}, {})", r1.seq, r2.seq, min_overlap, diff_limit, diff_percent_limit
+            );
         }
     }
-}
 
-fn count_diff(s1: &[u8], s2: &[u8], len: usize) -> usize {
-    let mut diff = 0;
-    for i in 0..len {
-        if s1[i] != s2[i] {
-            diff += 1;
+    #[test]
+    fn upstream_search_on_short_and_empty_reads() {
+        for (seq1, seq2) in [("", ""), ("ACGT", ""), ("", "ACGT"), ("ACGTAC", "GTACGT"), ("ACGTACG", "CGTACGT"), ("AAAAAAAAAAAA", "TTTTTTTT")] {
+            let (r1, r2) = (record(seq1), record(seq2));
+            for min_overlap in [0, 1, 4, 30] {
+                assert_eq!(
+                    OverlapAnalyzer::analyze_upstream(&r1, &r2, min_overlap, 5, 0.2),
+                    analyze_upstream_exhaustive(&r1, &r2, min_overlap, 5, 0.2),
+                    "{seq1} {seq2} (min {min_overlap})"
+                );
+            }
         }
+        // a 6-base insert read through on both mates, found without a seed
+        let res = OverlapAnalyzer::analyze_upstream(&record("ACGTAC"), &record("GTACGT"), 0, 5, 0.2);
+        assert!(res.overlapped);
+        assert_eq!((res.offset, res.overlap_len, res.diff), (0, 6, 0));
     }
-    diff
-}
 
+    #[test]
+    fn seeded_best_match_matches_every_offset() {
+        let mut rng = StdRng::seed_from_u64(5);
+        for _ in 0..5_000 {
+            let (r1, r2) = random_pair(&mut rng);
+            let (min_overlap, diff_limit) = (rng.random_range(0..=40), rng.random_range(0..=10));
+            let diff_percent_limit = rng.random_range(0..=30) as f32 / 100.0;
+            assert_eq!(
+                OverlapAnalyzer::analyze_with_params(&r1, &r2, min_overlap, diff_limit, diff_percent_limit),
+                analyze_exhaustive(&r1, &r2, min_overlap, diff_limit, diff_percent_limit),
+                "{} {} (min {}, limit {}, {})", r1.seq, r2.seq, min_overlap, diff_limit, diff_percent_limit
+            );
+        }
+    }
 
-fn reverse_complement(seq: &[u8]) -> String {
-    let mut res = String::with_capacity(seq.len());
-    for b in seq.iter().rev() {
-        res.push(match b {
-            b'A' => 'T',
-            b'T' => 'A',
-            b'C' => 'G',
-            b'G' => 'C',
-            b'N' => 'N',
-            _ => 'N',
-        });
+    #[test]
+    fn insert_size_counts_front_cuts() {
+        let cli = Cli::parse_from(["fastp_rs"]);
+        let fragment = "ACGTTGCATGCAAGTCCTAGGATCGATCGGCTAGCTTAGCATCGATGCTAGCTAGGCTAGCATCG";
+        let mut r2_seq = Vec::new();
+        reverse_complement_into(fragment.as_bytes(), &mut r2_seq);
+        let mut state = TrimState::new(record(fragment), Some(record(std::str::from_utf8(&r2_seq).unwrap())));
+        state.r1.cut_front(3);
+        state.r2.as_mut().unwrap().cut_front(2);
+        let mut report = Report::default();
+        OverlapCorrector::new(&cli).process(&mut state, &mut report);
+        assert_eq!(state.overlap.as_ref().map(|o| o.insert_size), Some(fragment.len() - 5));
+        assert_eq!(report.insert_sizes.get(&fragment.len()), Some(&1));
     }
-    res
 }
@@ -0,0 +1,150 @@
+use crate::config::Cli;
+use crate::fastq::FastqRecord;
+use super::overlap::OverlapResult;
+use super::processor::Processor;
+use super::Report;
+
+/// Default stage order, the one upstream fastp uses:
+/// fixed front/tail cuts and quality cutting (`trimAndCut`), polyG, then overlap analysis
+/// and base correction (PE), adapters, polyX, and finally `max_len`.
+/// The names are those of the stages in `Registry::builtin`.
+/// `primer` (amplicon primers, only active with `--primers`) runs right after the fixed cuts,
+/// and `mask` (only with `--mask_low_quality`/`--mask_adapter_window`) once the trimming is done.
+pub const DEFAULT_TRIM_ORDER: &str = "fixed,primer,quality,poly_g,overlap,adapter,poly_x,mask,max_len";
+
+/// A read on its way through the stages. `front_offset` counts the bases cut from
+/// its 5' end so far, so positions can be mapped back to the original read.
+pub struct TrimmedRead {
+    pub rec: FastqRecord,
+    pub front_offset: usize,
+    pub is_r2: bool,
+    /// set by the adapter stage when it cut adapter off this read
+    pub adapter_cut: bool,
+}
+
+impl TrimmedRead {
+    pub fn new(rec: FastqRecord, is_r2: bool) -> Self {
+        Self { rec, front_offset: 0, is_r2, adapter_cut: false }
+    }
+
+    /// Drop `n` bases from the 5' end.
+    pub fn cut_front(&mut self, n: usize) {
+        let n = n.min(self.rec.seq.len());
+        if n > 0 {
+            self.rec.seq.drain(..n);
+            self.rec.qual.drain(..n.min(self.rec.qual.len()));
+            self.front_offset += n;
+        }
+    }
+
+    /// Keep at most `len` bases.
+    pub fn truncate(&mut self, len: usize) {
+        self.rec.seq.truncate(len);
+        self.rec.qual.truncate(len);
+    }
+}
+
+/// A read or pair passing through the pipeline, with what earlier stages found out about it.
+pub struct TrimState {
+    pub r1: TrimmedRead,
+    pub r2: Option<TrimmedRead>,
+    /// set by the overlap stage (PE)
+    pub overlap: Option<OverlapResult>,
+    /// set by a stage that drops the read (pair); later stages are skipped
+    pub discarded: bool,
+    /// set by the adapter stage when (a mate of) the read is an adapter dimer
+    pub adapter_dimer: bool,
+}
+
+/// Why the stages dropped a read (pair).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Discard {
+    /// a stage filtered it out (e.g. no primer found)
+    Filtered,
+    /// an adapter dimer, routed to `--adapter_dimer_out`
+    AdapterDimer,
+}
+
+impl TrimState {
+    /// Why the read (pair) was dropped, if it was.
+    pub fn discard(&self) -> Option<Discard> {
+        match (self.discarded, self.adapter_dimer) {
+            (false, _) => None,
+            (true, false) => Some(Discard::Filtered),
+            (true, true) => Some(Discard::AdapterDimer),
+        }
+    }
+
+    pub fn new(r1: FastqRecord, r2: Option<FastqRecord>) -> Self {
+        Self {
+            r1: TrimmedRead::new(r1, false),
+            r2: r2.map(|r| TrimmedRead::new(r, true)),
+            overlap: None,
+            discarded: false,
+            adapter_dimer: false,
+        }
+    }
+
+    /// Current lengths of R1 and R2 (0 without R2).
+    pub fn lengths(&self) -> [usize; 2] {
+        [self.r1.rec.seq.len(), self.r2.as_ref().map_or(0, |r| r.rec.seq.len())]
+    }
+
+    /// Both reads of a pair, or the single read.
+    pub fn reads_mut(&mut self) -> impl Iterator<Item = &mut TrimmedRead> {
+        std::iter::once(&mut self.r1).chain(self.r2.as_mut())
+    }
+}
+
+/// `--trim_front1/2` and `--trim_tail1/2`.
+pub struct FixedTrimmer {
+    /// (front, tail) of R1 and R2
+    cuts: [(usize, usize); 2],
+}
+
+impl FixedTrimmer {
+    pub fn new(c: &Cli) -> Self {
+        Self { cuts: [(c.trim_front1, c.trim_tail1), (c.trim_front2, c.trim_tail2)] }
+    }
+}
+
+impl Processor for FixedTrimmer {
+    fn name(&self) -> &'static str {
+        "fixed"
+    }
+
+    fn process(&self, state: &mut TrimState, _report: &mut Report) {
+        for read in state.reads_mut() {
+            let (front, tail) = self.cuts[read.is_r2 as usize];
+            read.cut_front(front);
+            let len = read.rec.seq.len();
+            read.truncate(len.saturating_sub(tail));
+        }
+    }
+}
+
+/// `--max_len1/2`: keep at most that many bases (0 = no limit).
+pub struct MaxLenTrimmer {
+    max_len: [usize; 2],
+}
+
+impl MaxLenTrimmer {
+    pub fn new(c: &Cli) -> Self {
+        Self { max_len: [c.max_len1, c.max_len2] }
+    }
+}
+
+impl Processor for MaxLenTrimmer {
+    fn name(&self) -> &'static str {
+        "max_len"
+    }
+
+    fn process(&self, state: &mut TrimState, _report: &mut Report) {
+        for read in state.reads_mut() {
+            let max_len = self.max_len[read.is_r2 as usize];
+            if max_len > 0 {
+                read.truncate(max_len);
+            }
+        }
+    }
+}
@@ -1,8 +1,18 @@
+use crate::config::Cli;
 use crate::fastq::FastqRecord;
+use super::pipeline::TrimState;
+use super::processor::Processor;
 
-pub struct PolyGTrimmer;
+/// The `poly_g` stage; `min_len` is `None` when the trimming is off.
+pub struct PolyGTrimmer {
+    min_len: Option<usize>,
+}
 
 impl PolyGTrimmer {
+    pub fn new(c: &Cli) -> Self {
+        Self { min_len: (c.trim_poly_g && !c.disable_trim_poly_g).then_some(c.poly_g_min_len) }
+    }
+
     pub fn trim_poly_g(rec: &mut FastqRecord, min_len: usize, report: &mut super::Report) {
         const ALLOW_ONE_MISMATCH_FOR_EACH: usize = 8;
         const MAX_MISMATCH: usize = 5;
@@ -32,21 +42,26 @@ impl PolyGTrimmer {
         }
 
         // if the polyG length (i) >= min_len, trim it
-        if i >= min_len {
-            if first_g_pos < rlen {
-                 let trimmed_len = rlen - first_g_pos;
-                 // Update report (assuming we track polyG trimmed reads/bases?)
-                 // Currently report struct doesn't have polyG fields, but we can reuse or add them.
-                 // For now, let's just trim.
-                 // To match C++, we need to add fields to Report?
-                 // User asked to implement the function.
-                 
-                 // Note: C++ uses `firstGPos` which is the index of the first G in the valid PolyG tail.
-                 // So we truncate to `firstGPos`.
-                 report.poly_g_trimmed_reads += 1;
-                 report.poly_g_trimmed_bases += trimmed_len as u64;
-                 rec.seq.truncate(first_g_pos);
-                 rec.qual.truncate(first_g_pos);
+        if i >= min_len && first_g_pos < rlen {
+            let trimmed_len = rlen - first_g_pos;
+            // `first_g_pos` is the first G of the tail, as upstream's `firstGPos`
+            report.poly_g_trimmed_reads += 1;
+            report.poly_g_trimmed_bases += trimmed_len as u64;
+            rec.seq.truncate(first_g_pos);
+            rec.qual.truncate(first_g_pos);
+        }
+    }
+}
+
+impl Processor for PolyGTrimmer {
+    fn name(&self) -> &'static str {
+        "poly_g"
+    }
+
+    fn process(&self, state: &mut TrimState, report: &mut super::Report) {
+        if let Some(min_len) = self.min_len {
+            for read in state.reads_mut() {
+                Self::trim_poly_g(&mut read.rec, min_len, report);
             }
         }
     }
@@ -1,8 +1,18 @@
+use crate::config::Cli;
 use crate::fastq::FastqRecord;
+use super::pipeline::TrimState;
+use super::processor::Processor;
 
-pub struct PolyXTrimmer;
+/// The `poly_x` stage; `min_len` is `None` when the trimming is off.
+pub struct PolyXTrimmer {
+    min_len: Option<usize>,
+}
 
 impl PolyXTrimmer {
+    pub fn new(c: &Cli) -> Self {
+        Self { min_len: c.trim_poly_x.then_some(c.poly_x_min_len) }
+    }
+
     pub fn trim_poly_x(rec: &mut FastqRecord, min_len: usize, report: &mut super::Report) {
         const ALLOW_ONE_MISMATCH_FOR_EACH: usize = 8;
         const MAX_MISMATCH: usize = 5;
@@ -35,3 +45,17 @@ impl PolyXTrimmer {
         }
     }
 }
+
+impl Processor for PolyXTrimmer {
+    fn name(&self) -> &'static str {
+        "poly_x"
+    }
+
+    fn process(&self, state: &mut TrimState, report: &mut super::Report) {
+        if let Some(min_len) = self.min_len {
+            for read in state.reads_mut() {
+                Self::trim_poly_x(&mut read.rec, min_len, report);
+            }
+        }
+    }
+}
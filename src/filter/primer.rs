@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use crate::config::Cli;
+use crate::fastq::Reader;
+use crate::filter::matcher::Matcher;
+use super::pipeline::{TrimState, TrimmedRead};
+use super::processor::Processor;
+use super::Report;
+
+/// A PCR primer as it appears at the start of a read (5'->3').
+struct Primer {
+    amplicon: String,
+    seq: Vec<u8>,
+}
+
+/// The `primer` stage (amplicon panels): clips a forward or reverse primer from the
+/// 5' end of each read, matched with up to `mismatches` mismatches, and counts the
+/// reads (pairs) per amplicon. Reads without a primer are kept or, with
+/// `--primer_discard_unmatched`, dropped. Does nothing without `--primers`.
+pub struct PrimerTrimmer {
+    primers: Vec<Primer>,
+    mismatches: usize,
+    discard_unmatched: bool,
+}
+
+impl PrimerTrimmer {
+    pub fn new(c: &Cli) -> io::Result<Self> {
+        let primers = match &c.primers {
+            Some(path) => load_primers(path, c.primer_reference.as_deref())?,
+            None => Vec::new(),
+        };
+        Ok(Self { primers, mismatches: c.primer_mismatches, discard_unmatched: c.primer_discard_unmatched })
+    }
+
+    /// Best primer at the start of `seq`: fewest mismatches, then the longest.
+    fn find(&self, seq: &[u8]) -> Option<&Primer> {
+        let mut best: Option<(&Primer, usize)> = None;
+        for p in &self.primers {
+            if seq.len() < p.seq.len() {
+                continue;
+            }
+            let Some(diff) = Matcher::diff_within(&seq[..p.seq.len()], &p.seq, self.mismatches) else { continue };
+            let better = match best {
+                None => true,
+                Some((b, d)) => diff < d || (diff == d && p.seq.len() > b.seq.len()),
+            };
+            if better {
+                best = Some((p, diff));
+            }
+        }
+        best.map(|(p, _)| p)
+    }
+
+    /// Clip the primer found at the start of `read`; returns its amplicon.
+    fn clip<'a>(&'a self, read: &mut TrimmedRead) -> Option<&'a str> {
+        let primer = self.find(read.rec.seq.as_bytes())?;
+        read.cut_front(primer.seq.len());
+        Some(&primer.amplicon)
+    }
+}
+
+impl Processor for PrimerTrimmer {
+    fn name(&self) -> &'static str {
+        "primer"
+    }
+
+    fn process(&self, state: &mut TrimState, report: &mut Report) {
+        if self.primers.is_empty() {
+            return;
+        }
+        let amplicon1 = self.clip(&mut state.r1);
+        let amplicon2 = state.r2.as_mut().and_then(|r2| self.clip(r2));
+        match amplicon1.or(amplicon2) {
+            Some(amplicon) => {
+                match report.amplicons.get_mut(amplicon) {
+                    Some(n) => *n += 1,
+                    None => { report.amplicons.insert(amplicon.to_string(), 1); }
+                }
+            }
+            None if self.discard_unmatched => {
+                report.failed_no_primer += 1;
+                state.discarded = true;
+            }
+            None => {}
+        }
+    }
+}
+
+/// Load primers from FASTA, or from a primer BED (ARTIC style: chrom, start, end, name,
+/// pool, strand, and optionally the sequence; without it, the sequence is cut from `reference`).
+fn load_primers(path: &str, reference: Option<&str>) -> io::Result<Vec<Primer>> {
+    let text = fs::read_to_string(path)?;
+    let is_fasta = text.lines().find(|l| !l.trim().is_empty()).is_some_and(|l| l.starts_with('>'));
+    let mut primers = Vec::new();
+
+    if is_fasta {
+        let mut reader = Reader::new(Some(path), false, 1)?;
+        while let Some(rec) = reader.next_record()? {
+            let name = rec.name.trim_start_matches('@').split_whitespace().next().unwrap_or_default();
+            primers.push(Primer { amplicon: parse_primer_name(name).0, seq: rec.seq.to_ascii_uppercase().into_bytes() });
+        }
+    } else {
+        let mut genome: Option<HashMap<String, String>> = None;
+        for line in text.lines() {
+            if line.trim().is_empty() || line.starts_with('#') || line.starts_with("track") {
+                continue;
+            }
+            let cols: Vec<&str> = line.split('\t').collect();
+            if cols.len() < 4 {
+                return Err(bad_primer(path, line));
+            }
+            let name = cols[3];
+            let seq = match cols.get(6) {
+                Some(seq) if !seq.is_empty() => seq.to_ascii_uppercase(),
+                _ => {
+                    let Some(reference) = reference else {
+                        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("primer BED {} has no sequence column, give the genome with --primer_reference", path)));
+                    };
+                    if genome.is_none() {
+                        genome = Some(load_reference(reference)?);
+                    }
+                    let start: usize = cols[1].parse().map_err(|_| bad_primer(path, line))?;
+                    let end: usize = cols[2].parse().map_err(|_| bad_primer(path, line))?;
+                    let chrom = genome.as_ref().and_then(|g| g.get(cols[0]))
+                        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("primer {}: {} not in the reference", name, cols[0])))?;
+                    let region = chrom.get(start..end).ok_or_else(|| bad_primer(path, line))?.to_ascii_uppercase();
+                    let reverse = cols.get(5).map_or(parse_primer_name(name).1 == Some(true), |s| *s == "-");
+                    if reverse { reverse_complement(&region) } else { region }
+                }
+            };
+            primers.push(Primer { amplicon: parse_primer_name(name).0, seq: seq.into_bytes() });
+        }
+    }
+
+    if primers.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("no primers in {}", path)));
+    }
+    Ok(primers)
+}
+
+fn load_reference(path: &str) -> io::Result<HashMap<String, String>> {
+    let mut reader = Reader::new(Some(path), false, 1)?;
+    let mut genome = HashMap::new();
+    while let Some(rec) = reader.next_record()? {
+        let name = rec.name.trim_start_matches('@').split_whitespace().next().unwrap_or_default().to_string();
+        genome.insert(name, rec.seq);
+    }
+    Ok(genome)
+}
+
+const FORWARD_SUFFIXES: [&str; 4] = ["_LEFT", "_FWD", "_FW", "_F"];
+const REVERSE_SUFFIXES: [&str; 4] = ["_RIGHT", "_REV", "_RV", "_R"];
+
+/// Split a primer name like `nCoV-2019_1_LEFT_alt1` into its amplicon (`nCoV-2019_1`)
+/// and whether it is a reverse primer (`None` when the name does not say).
+fn parse_primer_name(primer: &str) -> (String, Option<bool>) {
+    let upper = primer.to_ascii_uppercase();
+    let end = upper.rfind("_ALT").filter(|&i| upper[i + 4..].bytes().all(|b| b.is_ascii_digit())).unwrap_or(upper.len());
+    let base = &upper[..end];
+    for (suffixes, reverse) in [(&FORWARD_SUFFIXES, false), (&REVERSE_SUFFIXES, true)] {
+        if let Some(s) = suffixes.iter().find(|s| base.ends_with(*s)) {
+            return (primer[..end - s.len()].to_string(), Some(reverse));
+        }
+    }
+    (primer[..end].to_string(), None)
+}
+
+fn reverse_complement(seq: &str) -> String {
+    seq.bytes().rev().map(|b| match b {
+        b'A' => 'T',
+        b'T' => 'A',
+        b'C' => 'G',
+        b'G' => 'C',
+        _ => 'N',
+    }).collect()
+}
+
+fn bad_primer(path: &str, line: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("bad primer BED line in {}: {}", path, line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fastq::FastqRecord;
+
+    fn rec(seq: &str) -> FastqRecord {
+        FastqRecord { name: "@r".into(), seq: seq.into(), plus: "+".into(), qual: "I".repeat(seq.len()) }
+    }
+
+    #[test]
+    fn primer_names_give_amplicon_and_strand() {
+        assert_eq!(parse_primer_name("nCoV-2019_1_LEFT"), ("nCoV-2019_1".to_string(), Some(false)));
+        assert_eq!(parse_primer_name("nCoV-2019_1_RIGHT"), ("nCoV-2019_1".to_string(), Some(true)));
+        assert_eq!(parse_primer_name("nCoV-2019_14_LEFT_alt4"), ("nCoV-2019_14".to_string(), Some(false)));
+        assert_eq!(parse_primer_name("amp7_rev"), ("amp7".to_string(), Some(true)));
+        assert_eq!(parse_primer_name("amp7_F"), ("amp7".to_string(), Some(false)));
+        // `_ALT` only counts with a number (or nothing) after it
+        assert_eq!(parse_primer_name("amp_altered"), ("amp_altered".to_string(), None));
+        assert_eq!(parse_primer_name("amp9"), ("amp9".to_string(), None));
+    }
+
+    #[test]
+    fn bed_primers_are_cut_from_the_reference() {
+        let dir = std::env::temp_dir().join(format!("fastp_rs_primer_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let reference = dir.join("ref.fa");
+        let bed = dir.join("primers.bed");
+        fs::write(&reference, ">chr1\nAAAACCCCGGGGTTTTACGTACGT\n").unwrap();
+        fs::write(&bed, "chr1\t0\t8\tamp1_LEFT\t1\t+\nchr1\t12\t20\tamp1_RIGHT\t1\t-\nchr1\t4\t8\tamp2_LEFT\t2\t+\tGATTACA\n").unwrap();
+        let primers = load_primers(bed.to_str().unwrap(), Some(reference.to_str().unwrap())).unwrap();
+        let got: Vec<(&str, &[u8])> = primers.iter().map(|p| (p.amplicon.as_str(), p.seq.as_slice())).collect();
+        assert_eq!(got, [("amp1", &b"AAAACCCC"[..]), ("amp1", b"ACGTAAAA"), ("amp2", b"GATTACA")]);
+        assert!(load_primers(bed.to_str().unwrap(), None).is_err(), "BED without sequences needs the reference");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn clips_the_best_primer_and_counts_amplicons() {
+        let trimmer = PrimerTrimmer {
+            primers: vec![
+                Primer { amplicon: "short".into(), seq: b"ACGTAC".to_vec() },
+                Primer { amplicon: "long".into(), seq: b"ACGTACGG".to_vec() },
+            ],
+            mismatches: 1,
+            discard_unmatched: true,
+        };
+        let mut report = Report::default();
+        // both match with no mismatch: the longer one wins
+        let mut state = TrimState::new(rec("ACGTACGGTTTT"), None);
+        trimmer.process(&mut state, &mut report);
+        assert_eq!(state.r1.rec.seq, "TTTT");
+        assert_eq!(state.r1.front_offset, 8);
+        // one mismatch in the long primer, none in the short one
+        let mut state = TrimState::new(rec("ACGTACTGTTTT"), None);
+        trimmer.process(&mut state, &mut report);
+        assert_eq!(state.r1.rec.seq, "TGTTTT");
+        // no primer: dropped
+        let mut state = TrimState::new(rec("TTTTTTTTTTTT"), None);
+        trimmer.process(&mut state, &mut report);
+        assert!(state.discarded);
+        assert_eq!(report.amplicons.get("long"), Some(&1));
+        assert_eq!(report.amplicons.get("short"), Some(&1));
+        assert_eq!(report.failed_no_primer, 1);
+    }
+}
@@ -0,0 +1,89 @@
+use std::io;
+use serde::Serialize;
+use crate::config::Cli;
+use super::pipeline::TrimState;
+use super::Report;
+use super::adapter_trimmer::AdapterTrimmer;
+use super::mask::LowQualityMasker;
+use super::overlap::OverlapCorrector;
+use super::pipeline::{FixedTrimmer, MaxLenTrimmer};
+use super::poly_g::PolyGTrimmer;
+use super::poly_x::PolyXTrimmer;
+use super::primer::PrimerTrimmer;
+use super::sliding_window::QualityCutter;
+
+/// One trimming stage of `Filter`.
+///
+/// A processor works on a read (SE) or pair (PE) in `TrimState` and may add its own
+/// counters to the `Report`; the bases and reads it trims are also tallied per stage
+/// under its `name` in `Report::stages`.
+pub trait Processor: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn process(&self, state: &mut TrimState, report: &mut Report);
+}
+
+type Factory = Box<dyn Fn(&Cli) -> io::Result<Box<dyn Processor>> + Send + Sync>;
+
+/// Processors by name, for building the pipeline from `--trim_order`.
+/// In-house stages are added with `register` before the `Filter` is built.
+pub struct Registry {
+    factories: Vec<(&'static str, Factory)>,
+}
+
+impl Registry {
+    /// The stages that ship with fastp-rs.
+    pub fn builtin() -> Self {
+        let mut registry = Registry { factories: Vec::new() };
+        registry.register("fixed", |c| Ok(Box::new(FixedTrimmer::new(c))));
+        registry.register("primer", |c| Ok(Box::new(PrimerTrimmer::new(c)?)));
+        registry.register("quality", |c| Ok(Box::new(QualityCutter::new(c))));
+        registry.register("overlap", |c| Ok(Box::new(OverlapCorrector::new(c))));
+        registry.register("poly_g", |c| Ok(Box::new(PolyGTrimmer::new(c))));
+        registry.register("adapter", |c| Ok(Box::new(AdapterTrimmer::new(c))));
+        registry.register("poly_x", |c| Ok(Box::new(PolyXTrimmer::new(c))));
+        registry.register("mask", |c| Ok(Box::new(LowQualityMasker::new(c))));
+        registry.register("max_len", |c| Ok(Box::new(MaxLenTrimmer::new(c))));
+        registry
+    }
+
+    /// Add a stage, or replace the one with the same name.
+    pub fn register<F>(&mut self, name: &'static str, factory: F)
+    where
+        F: Fn(&Cli) -> io::Result<Box<dyn Processor>> + Send + Sync + 'static,
+    {
+        self.factories.retain(|(n, _)| *n != name);
+        self.factories.push((name, Box::new(factory)));
+    }
+
+    /// Build the stages named in a comma-separated `order`; stages left out are not run.
+    pub fn build(&self, order: &str, config: &Cli) -> io::Result<Vec<Box<dyn Processor>>> {
+        let mut names: Vec<&str> = Vec::new();
+        let mut stages = Vec::new();
+        for name in order.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            let Some((_, factory)) = self.factories.iter().find(|(n, _)| *n == name) else {
+                let known: Vec<_> = self.factories.iter().map(|(n, _)| *n).collect();
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unknown trimming stage {} (known: {})", name, known.join(", "))));
+            };
+            if names.contains(&name) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("trimming stage {} listed twice", name)));
+            }
+            names.push(name);
+            stages.push(factory(config)?);
+        }
+        Ok(stages)
+    }
+}
+
+/// What one stage trimmed, summed over R1 and R2.
+#[derive(Default, Serialize, Clone, Debug)]
+pub struct StageStats {
+    pub trimmed_reads: u64,
+    pub trimmed_bases: u64,
+}
+
+impl StageStats {
+    pub fn merge(&mut self, other: &StageStats) {
+        self.trimmed_reads += other.trimmed_reads;
+        self.trimmed_bases += other.trimmed_bases;
+    }
+}
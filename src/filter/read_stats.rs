@@ -0,0 +1,176 @@
+use serde::Serialize;
+use crate::fastq::FastqRecord;
+
+/// Per-cycle curves stop here, so long reads do not make every pack's report huge;
+/// the totals still count all bases.
+const MAX_CYCLES: usize = 1000;
+
+/// Index of each byte in the per-cycle tallies: A, T, C, G (upstream's curve order), anything else N.
+const fn base_index_table() -> [u8; 256] {
+    let mut table = [4u8; 256];
+    table[b'A' as usize] = 0;
+    table[b'T' as usize] = 1;
+    table[b'C' as usize] = 2;
+    table[b'G' as usize] = 3;
+    table[b'a' as usize] = 0;
+    table[b't' as usize] = 1;
+    table[b'c' as usize] = 2;
+    table[b'g' as usize] = 3;
+    table
+}
+
+static BASE_INDEX: [u8; 256] = base_index_table();
+
+/// Base counts and qualities at one read position.
+#[derive(Default, Clone, Copy)]
+pub struct CycleStats {
+    /// bases as A, T, C, G, N
+    pub bases: [u64; 5],
+    /// quality sums (phred) of those bases
+    pub qual_sums: [u64; 5],
+}
+
+/// Base and quality statistics of a set of reads (one mate, before or after filtering).
+/// The rates and the mean length are derived by `finalize`.
+#[derive(Default, Clone, Serialize)]
+pub struct ReadStats {
+    pub reads: u64,
+    pub bases: u64,
+    pub q20_bases: u64,
+    pub q30_bases: u64,
+    pub gc_bases: u64,
+    pub q20_rate: f64,
+    pub q30_rate: f64,
+    pub gc_content: f64,
+    pub min_len: usize,
+    pub max_len: usize,
+    pub mean_len: f64,
+    #[serde(skip)]
+    pub cycles: Vec<CycleStats>,
+}
+
+impl ReadStats {
+    pub fn add(&mut self, rec: &FastqRecord) {
+        let (seq, qual) = (rec.seq.as_bytes(), rec.qual.as_bytes());
+        let len = seq.len();
+        self.min_len = if self.reads == 0 { len } else { self.min_len.min(len) };
+        self.max_len = self.max_len.max(len);
+        self.reads += 1;
+        self.bases += len as u64;
+        let cycles = len.min(MAX_CYCLES);
+        if self.cycles.len() < cycles {
+            self.cycles.resize(cycles, CycleStats::default());
+        }
+        for (i, &b) in seq.iter().enumerate() {
+            let q = qual.get(i).map_or(0, |q| q.saturating_sub(33)) as u64;
+            let base = BASE_INDEX[b as usize] as usize;
+            self.q20_bases += (q >= 20) as u64;
+            self.q30_bases += (q >= 30) as u64;
+            self.gc_bases += (base == 2 || base == 3) as u64;
+            if let Some(cycle) = self.cycles.get_mut(i) {
+                cycle.bases[base] += 1;
+                cycle.qual_sums[base] += q;
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: &ReadStats) {
+        if other.reads == 0 {
+            return;
+        }
+        self.min_len = if self.reads == 0 { other.min_len } else { self.min_len.min(other.min_len) };
+        self.max_len = self.max_len.max(other.max_len);
+        self.reads += other.reads;
+        self.bases += other.bases;
+        self.q20_bases += other.q20_bases;
+        self.q30_bases += other.q30_bases;
+        self.gc_bases += other.gc_bases;
+        if self.cycles.len() < other.cycles.len() {
+            self.cycles.resize(other.cycles.len(), CycleStats::default());
+        }
+        for (cycle, o) in self.cycles.iter_mut().zip(&other.cycles) {
+            for i in 0..5 {
+                cycle.bases[i] += o.bases[i];
+                cycle.qual_sums[i] += o.qual_sums[i];
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reads == 0
+    }
+
+    pub fn finalize(&mut self) {
+        self.q20_rate = self.rate(self.q20_bases);
+        self.q30_rate = self.rate(self.q30_bases);
+        self.gc_content = self.rate(self.gc_bases);
+        self.mean_len = if self.reads == 0 { 0.0 } else { self.bases as f64 / self.reads as f64 };
+    }
+
+    /// Mean length, rounded down as upstream reports it.
+    pub fn mean_len_floor(&self) -> usize {
+        self.bases.checked_div(self.reads).unwrap_or(0) as usize
+    }
+
+    pub fn rate(&self, bases: u64) -> f64 {
+        if self.bases == 0 { 0.0 } else { bases as f64 / self.bases as f64 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rec(seq: &str, qual: &str) -> FastqRecord {
+        FastqRecord { name: "@r".into(), seq: seq.into(), plus: "+".into(), qual: qual.into() }
+    }
+
+    #[test]
+    fn counts_bases_qualities_and_lengths() {
+        let mut stats = ReadStats::default();
+        // qualities 40, 25, 10 and 30
+        stats.add(&rec("ACgN", "I:+?"));
+        stats.add(&rec("GG", "II"));
+        stats.finalize();
+        assert_eq!((stats.reads, stats.bases, stats.min_len, stats.max_len), (2, 6, 2, 4));
+        assert_eq!((stats.q20_bases, stats.q30_bases, stats.gc_bases), (5, 4, 4));
+        assert_eq!(stats.mean_len, 3.0);
+        assert_eq!(stats.mean_len_floor(), 3);
+        assert!((stats.gc_content - 4.0 / 6.0).abs() < 1e-9);
+        assert_eq!(stats.cycles.len(), 4);
+        assert_eq!(stats.cycles[0].bases, [1, 0, 0, 1, 0]);
+        assert_eq!(stats.cycles[0].qual_sums, [40, 0, 0, 40, 0]);
+        assert_eq!(stats.cycles[3].bases, [0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn merging_matches_adding_everything_to_one() {
+        let reads = [rec("ACGTACGT", "IIII####"), rec("TTT", "+++"), rec("GATTACA", "5555555")];
+        let mut whole = ReadStats::default();
+        reads.iter().for_each(|r| whole.add(r));
+        let mut first = ReadStats::default();
+        first.add(&reads[1]);
+        let mut rest = ReadStats::default();
+        rest.add(&reads[0]);
+        rest.add(&reads[2]);
+        let mut merged = ReadStats::default();
+        merged.merge(&ReadStats::default());
+        merged.merge(&first);
+        merged.merge(&rest);
+        assert_eq!((merged.reads, merged.bases, merged.min_len, merged.max_len), (whole.reads, whole.bases, whole.min_len, whole.max_len));
+        assert_eq!((merged.q20_bases, merged.q30_bases, merged.gc_bases), (whole.q20_bases, whole.q30_bases, whole.gc_bases));
+        assert_eq!(merged.cycles.len(), whole.cycles.len());
+        for (m, w) in merged.cycles.iter().zip(&whole.cycles) {
+            assert_eq!((m.bases, m.qual_sums), (w.bases, w.qual_sums));
+        }
+    }
+
+    #[test]
+    fn long_reads_keep_totals_past_the_cycle_cap() {
+        let mut stats = ReadStats::default();
+        stats.add(&rec(&"A".repeat(MAX_CYCLES + 10), &"I".repeat(MAX_CYCLES + 10)));
+        assert_eq!(stats.cycles.len(), MAX_CYCLES);
+        assert_eq!(stats.bases, (MAX_CYCLES + 10) as u64);
+        assert_eq!(stats.q30_bases, (MAX_CYCLES + 10) as u64);
+    }
+}
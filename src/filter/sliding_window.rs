@@ -1,5 +1,107 @@
+use serde::Serialize;
 use crate::fastq::FastqRecord;
 use crate::config::Cli;
+use super::long_read::LongReadTrimmer;
+use super::mott::MottTrimmer;
+use super::pipeline::{TrimState, TrimmedRead};
+use super::processor::Processor;
+use super::Report;
+
+/// Quality cutting settings of one mate; R2 may use its own windows and thresholds.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QualityCut {
+    /// (window size, mean quality) per sliding-window method
+    pub front: Option<(usize, u8)>,
+    pub tail: Option<(usize, u8)>,
+    pub right: Option<(usize, u8)>,
+    /// quality threshold of the Mott/BWA running-sum trimming
+    pub mott: Option<u8>,
+}
+
+impl QualityCut {
+    /// Settings for R1, or for R2 where each `*_r2` option falls back to its R1 value.
+    pub fn for_read(c: &Cli, is_r2: bool) -> Self {
+        let pick = |r1: usize, r2: Option<usize>| if is_r2 { r2.unwrap_or(r1) } else { r1 };
+        let pick_q = |r1: u8, r2: Option<u8>| if is_r2 { r2.unwrap_or(r1) } else { r1 };
+        Self {
+            front: c.cut_front.then(|| (pick(c.cut_front_window_size, c.cut_front_window_size_r2), pick_q(c.cut_front_mean_quality, c.cut_front_mean_quality_r2))),
+            tail: c.cut_tail.then(|| (pick(c.cut_tail_window_size, c.cut_tail_window_size_r2), pick_q(c.cut_tail_mean_quality, c.cut_tail_mean_quality_r2))),
+            right: c.cut_right.then(|| (pick(c.cut_right_window_size, c.cut_right_window_size_r2), pick_q(c.cut_right_mean_quality, c.cut_right_mean_quality_r2))),
+            mott: c.cut_mott.then(|| pick_q(c.cut_mott_quality, c.cut_mott_quality_r2)),
+        }
+    }
+}
+
+/// Bases removed by each quality cutting method, for one mate.
+#[derive(Default, Serialize, Clone, Debug)]
+pub struct QualityCutStats {
+    pub front_bases: u64,
+    pub tail_bases: u64,
+    pub right_bases: u64,
+    pub mott_bases: u64,
+}
+
+impl QualityCutStats {
+    pub fn merge(&mut self, other: &QualityCutStats) {
+        self.front_bases += other.front_bases;
+        self.tail_bases += other.tail_bases;
+        self.right_bases += other.right_bases;
+        self.mott_bases += other.mott_bases;
+    }
+}
+
+/// The `quality` stage: sliding-window cuts, then Mott trimming, then
+/// (long-read mode) head/tail trimming.
+pub struct QualityCutter {
+    /// settings of R1 and R2
+    cut: [QualityCut; 2],
+    /// (window, head quality, tail quality) in long-read mode
+    long_read: Option<(usize, u8, u8)>,
+}
+
+impl QualityCutter {
+    pub fn new(c: &Cli) -> Self {
+        Self {
+            cut: [QualityCut::for_read(c, false), QualityCut::for_read(c, true)],
+            long_read: c.long_read.then_some((c.long_trim_window, c.trim_head_quality, c.trim_tail_quality)),
+        }
+    }
+
+    fn cut_read(&self, read: &mut TrimmedRead, report: &mut Report) {
+        // Sliding Window Quality Cutting
+        let cut = &self.cut[read.is_r2 as usize];
+        let cut_stats = if read.is_r2 { &mut report.quality_cut_r2 } else { &mut report.quality_cut_r1 };
+        let mut front_trimmed = 0;
+        SlidingWindow::trim_and_cut(&mut read.rec, cut, &mut front_trimmed, cut_stats);
+        read.front_offset += front_trimmed;
+
+        // Running-sum (Mott/BWA) quality trimming
+        if let Some(quality) = cut.mott {
+            let (front, tail) = MottTrimmer::trim(&mut read.rec, quality);
+            cut_stats.mott_bases += (front + tail) as u64;
+            read.front_offset += front;
+        }
+
+        if let Some((window, head_quality, tail_quality)) = self.long_read {
+            let (head, tail) = LongReadTrimmer::trim_ends(&mut read.rec, window, head_quality, tail_quality);
+            report.head_quality_trimmed_bases += head as u64;
+            report.tail_quality_trimmed_bases += tail as u64;
+            read.front_offset += head;
+        }
+    }
+}
+
+impl Processor for QualityCutter {
+    fn name(&self) -> &'static str {
+        "quality"
+    }
+
+    fn process(&self, state: &mut TrimState, report: &mut Report) {
+        for read in state.reads_mut() {
+            self.cut_read(read, report);
+        }
+    }
+}
 
 pub struct SlidingWindow;
 
@@ -8,8 +110,9 @@ impl SlidingWindow {
     /// Ported from fastp C++ Filter::trimAndCut
     pub fn trim_and_cut(
         rec: &mut FastqRecord,
-        config: &Cli,
-        front_trimmed: &mut usize
+        cut: &QualityCut,
+        front_trimmed: &mut usize,
+        stats: &mut QualityCutStats,
     ) -> bool {
         let mut front = if *front_trimmed > 0 { *front_trimmed } else { 
             // This usually starts with static trim options which we handled before calling this?
@@ -37,9 +140,8 @@ impl SlidingWindow {
         let l = rec.seq.len();
         
         // 1. Quality Cut Front (5')
-        if config.cut_front {
-            let w = config.cut_front_window_size;
-            let mean_qual = config.cut_front_mean_quality as f32 + 33.0; // Phred+33
+        if let Some((w, quality)) = cut.front {
+            let mean_qual = quality as f32 + 33.0; // Phred+33
             
             if l >= w {
                 let qual_bytes = rec.qual.as_bytes();
@@ -49,8 +151,8 @@ impl SlidingWindow {
                 let mut total_qual = 0;
                 
                 // Initialize window
-                for i in 0..w-1 {
-                    total_qual += qual_bytes[i] as i32;
+                for &q in &qual_bytes[..w-1] {
+                    total_qual += q as i32;
                 }
                 
                 let mut found = false;
@@ -149,9 +251,8 @@ impl SlidingWindow {
         // "Scan from beginning, if we find a bad window, we cut everything from there to the end."
         // This is different from cut_tail (which scans from end).
         let mut right_cut_len = 0; // Amount to cut from right
-        if config.cut_right {
-             let w = config.cut_right_window_size;
-             let mean_qual = config.cut_right_mean_quality as f32 + 33.0;
+        if let Some((w, quality)) = cut.right {
+             let mean_qual = quality as f32 + 33.0;
              let current_l = l.saturating_sub(front); // Length after front trim
              
              if current_l >= w {
@@ -162,8 +263,8 @@ impl SlidingWindow {
                  let mut total_qual = 0;
                  
                  // Init window
-                 for i in 0..w-1 {
-                     total_qual += qual_bytes[i] as i32;
+                 for &q in &qual_bytes[..w-1] {
+                     total_qual += q as i32;
                  }
                  
                  let mut found_bad = false;
@@ -219,9 +320,8 @@ impl SlidingWindow {
         // Only if cut_right is NOT enabled (C++ logic prefers cut_right over cut_tail if both? No, "if(!mOptions->qualityCut.enabledRight && mOptions->qualityCut.enabledTail)")
         // So they are mutually exclusive in C++ implementation for the tail side?
         // Yes.
-        if !config.cut_right && config.cut_tail {
-             let w = config.cut_tail_window_size;
-             let mean_qual = config.cut_tail_mean_quality as f32 + 33.0;
+        if let (None, Some((w, quality))) = (cut.right, cut.tail) {
+             let mean_qual = quality as f32 + 33.0;
              let current_l = l.saturating_sub(front);
              
              if current_l >= w {
@@ -339,6 +439,15 @@ impl SlidingWindow {
         // Apply Trimming
         if front > 0 || tail > 0 || right_cut_len > 0 {
             let total_cut_right = tail.max(right_cut_len);
+            // cut_right and cut_tail are exclusive, so the 3' cut belongs to one of them
+            let front_cut = front.min(l);
+            let back_cut = total_cut_right.min(l - front_cut);
+            stats.front_bases += front_cut as u64;
+            if cut.right.is_some() {
+                stats.right_bases += back_cut as u64;
+            } else {
+                stats.tail_bases += back_cut as u64;
+            }
             let new_len = l.saturating_sub(front).saturating_sub(total_cut_right);
             
             if new_len == 0 {
@@ -1,6 +1,10 @@
+use std::collections::BTreeSet;
 use std::fs::File;
 use std::io::{self, Write};
-use crate::filter::Report;
+use crate::filter::{ReadStats, Report};
+
+/// Formats one statistic of a read set for a table cell.
+type StatCell = dyn Fn(&ReadStats) -> String;
 
 pub fn write_html_report(path: &str, report: &Report, title: &str) -> io::Result<()> {
     let mut f = File::create(path)?;
@@ -30,13 +34,145 @@ pub fn write_html_report(path: &str, report: &Report, title: &str) -> io::Result
     writeln!(f, "<tr><td>Failed (Low Quality)</td><td>{}</td></tr>", report.failed_low_quality)?;
     writeln!(f, "<tr><td>Failed (Too many N)</td><td>{}</td></tr>", report.failed_n_excess)?;
     writeln!(f, "<tr><td>Failed (Low Avg Qual)</td><td>{}</td></tr>", report.failed_low_average_qual)?;
+    if let Some(rate) = report.duplication_rate {
+        writeln!(f, "<tr><td>Duplication Rate</td><td>{:.2}%</td></tr>", rate * 100.0)?;
+    }
+    if let Some(scheme) = &report.quality_binning {
+        writeln!(f, "<tr><td>Output Quality Bins</td><td>{}</td></tr>", scheme)?;
+    }
+    writeln!(f, "</table>")?;
+
+    // raw and clean reads, per mate
+    let mut columns = vec![("Read 1 Raw", &report.before_r1), ("Read 1 Clean", &report.after_r1)];
+    if !report.before_r2.is_empty() {
+        columns.extend([("Read 2 Raw", &report.before_r2), ("Read 2 Clean", &report.after_r2)]);
+    }
+    writeln!(f, "<table>")?;
+    write!(f, "<tr><th>Metric</th>")?;
+    for (name, _) in &columns {
+        write!(f, "<th>{}</th>", name)?;
+    }
+    writeln!(f, "</tr>")?;
+    let rows: [(&str, &StatCell); 6] = [
+        ("Reads", &|s| s.reads.to_string()),
+        ("Bases", &|s| s.bases.to_string()),
+        ("Q20 Bases", &|s| format!("{} ({:.2}%)", s.q20_bases, s.q20_rate * 100.0)),
+        ("Q30 Bases", &|s| format!("{} ({:.2}%)", s.q30_bases, s.q30_rate * 100.0)),
+        ("GC Content", &|s| format!("{:.2}%", s.gc_content * 100.0)),
+        ("Length (min / mean / max)", &|s| format!("{} / {:.1} / {}", s.min_len, s.mean_len, s.max_len)),
+    ];
+    for (metric, value) in rows {
+        write!(f, "<tr><td>{}</td>", metric)?;
+        for (_, stats) in &columns {
+            write!(f, "<td>{}</td>", value(stats))?;
+        }
+        writeln!(f, "</tr>")?;
+    }
+    writeln!(f, "</table>")?;
+
+    writeln!(f, "<h2>Input Files</h2>")?;
+    writeln!(f, "<table>")?;
+    writeln!(f, "<tr><th>File</th><th>Reads</th></tr>")?;
+    for input in &report.input_files {
+        writeln!(f, "<tr><td>{}</td><td>{}</td></tr>", input.path, input.reads)?;
+    }
     writeln!(f, "</table>")?;
 
+    if let Some(len) = &report.read_length {
+        writeln!(f, "<h2>Long Read Statistics</h2>")?;
+        writeln!(f, "<table>")?;
+        writeln!(f, "<tr><th>Metric</th><th>Value</th></tr>")?;
+        writeln!(f, "<tr><td>Reads</td><td>{}</td></tr>", len.reads)?;
+        writeln!(f, "<tr><td>Bases</td><td>{}</td></tr>", len.bases)?;
+        writeln!(f, "<tr><td>Mean Length</td><td>{:.1}</td></tr>", len.mean)?;
+        writeln!(f, "<tr><td>Min / Max Length</td><td>{} / {}</td></tr>", len.min, len.max)?;
+        writeln!(f, "<tr><td>N50 / N90</td><td>{} / {}</td></tr>", len.n50, len.n90)?;
+        for (name, value) in &len.percentiles {
+            writeln!(f, "<tr><td>Length {}</td><td>{}</td></tr>", name, value)?;
+        }
+        writeln!(f, "<tr><td>Failed (Low Mean Qual)</td><td>{}</td></tr>", report.failed_low_mean_qual)?;
+        writeln!(f, "<tr><td>Head / Tail Quality Trimmed Bases</td><td>{} / {}</td></tr>", report.head_quality_trimmed_bases, report.tail_quality_trimmed_bases)?;
+        writeln!(f, "</table>")?;
+    }
+
+    if !report.amplicons.is_empty() {
+        writeln!(f, "<h2>Amplicons</h2>")?;
+        writeln!(f, "<table>")?;
+        writeln!(f, "<tr><th>Amplicon</th><th>Reads</th></tr>")?;
+        for (amplicon, reads) in &report.amplicons {
+            writeln!(f, "<tr><td>{}</td><td>{}</td></tr>", amplicon, reads)?;
+        }
+        writeln!(f, "<tr><td>Failed (No Primer)</td><td>{}</td></tr>", report.failed_no_primer)?;
+        writeln!(f, "</table>")?;
+    }
+
     writeln!(f, "<h2>Adapter Trimming</h2>")?;
     writeln!(f, "<table>")?;
     writeln!(f, "<tr><th>Metric</th><th>Value</th></tr>")?;
     writeln!(f, "<tr><td>Trimmed Reads</td><td>{}</td></tr>", report.adapter_trimmed_reads)?;
     writeln!(f, "<tr><td>Trimmed Bases</td><td>{}</td></tr>", report.adapter_trimmed_bases)?;
+    writeln!(f, "<tr><td>Adapter Dimers</td><td>{} ({:.2}%)</td></tr>", report.adapter_dimer_reads, report.adapter_dimer_percent)?;
+    writeln!(f, "<tr><td>Short Inserts</td><td>{}</td></tr>", report.short_insert_reads)?;
+    writeln!(f, "</table>")?;
+    writeln!(f, "<table>")?;
+    writeln!(f, "<tr><th>Metric</th><th>Read 1</th><th>Read 2</th></tr>")?;
+    let (a1, a2) = (&report.adapter_r1, &report.adapter_r2);
+    writeln!(f, "<tr><td>Trimmed by Overlap (reads / bases)</td><td>{} / {}</td><td>{} / {}</td></tr>", a1.overlap_trimmed_reads, a1.overlap_trimmed_bases, a2.overlap_trimmed_reads, a2.overlap_trimmed_bases)?;
+    writeln!(f, "<tr><td>Trimmed by Sequence (reads / bases)</td><td>{} / {}</td><td>{} / {}</td></tr>", a1.sequence_trimmed_reads, a1.sequence_trimmed_bases, a2.sequence_trimmed_reads, a2.sequence_trimmed_bases)?;
+    writeln!(f, "</table>")?;
+    for (mate, stats) in [("Read 1", a1), ("Read 2", a2)] {
+        if stats.adapter_counts.is_empty() {
+            continue;
+        }
+        writeln!(f, "<h3>{} Trimmed Adapters</h3>", mate)?;
+        writeln!(f, "<table>")?;
+        writeln!(f, "<tr><th>Sequence</th><th>Reads</th></tr>")?;
+        // most frequent first, `others` last
+        let mut counts: Vec<_> = stats.adapter_counts.iter().filter(|(seq, _)| *seq != "others").collect();
+        counts.sort_by(|a, b| b.1.cmp(a.1));
+        for (seq, n) in counts {
+            writeln!(f, "<tr><td><code>{}</code></td><td>{}</td></tr>", seq, n)?;
+        }
+        if let Some(n) = stats.adapter_counts.get("others") {
+            writeln!(f, "<tr><td>others</td><td>{}</td></tr>", n)?;
+        }
+        writeln!(f, "</table>")?;
+    }
+    if !a1.trimmed_lengths.is_empty() || !a2.trimmed_lengths.is_empty() {
+        writeln!(f, "<h3>Trimmed Adapter Lengths</h3>")?;
+        writeln!(f, "<table>")?;
+        writeln!(f, "<tr><th>Trimmed Bases</th><th>Read 1</th><th>Read 2</th></tr>")?;
+        let lengths: BTreeSet<usize> = a1.trimmed_lengths.keys().chain(a2.trimmed_lengths.keys()).copied().collect();
+        for len in lengths {
+            let (n1, n2) = (a1.trimmed_lengths.get(&len).copied().unwrap_or(0), a2.trimmed_lengths.get(&len).copied().unwrap_or(0));
+            writeln!(f, "<tr><td>{}</td><td>{}</td><td>{}</td></tr>", len, n1, n2)?;
+        }
+        writeln!(f, "</table>")?;
+    }
+
+    writeln!(f, "<h2>Trimming Stages</h2>")?;
+    writeln!(f, "<table>")?;
+    writeln!(f, "<tr><th>Stage</th><th>Trimmed Reads</th><th>Trimmed Bases</th></tr>")?;
+    for (name, stats) in &report.stages {
+        writeln!(f, "<tr><td>{}</td><td>{}</td><td>{}</td></tr>", name, stats.trimmed_reads, stats.trimmed_bases)?;
+    }
+    writeln!(f, "</table>")?;
+
+    writeln!(f, "<h2>Quality Cutting (Trimmed Bases)</h2>")?;
+    writeln!(f, "<table>")?;
+    writeln!(f, "<tr><th>Method</th><th>Read 1</th><th>Read 2</th></tr>")?;
+    let (q1, q2) = (&report.quality_cut_r1, &report.quality_cut_r2);
+    writeln!(f, "<tr><td>Cut Front (window)</td><td>{}</td><td>{}</td></tr>", q1.front_bases, q2.front_bases)?;
+    writeln!(f, "<tr><td>Cut Tail (window)</td><td>{}</td><td>{}</td></tr>", q1.tail_bases, q2.tail_bases)?;
+    writeln!(f, "<tr><td>Cut Right (window)</td><td>{}</td><td>{}</td></tr>", q1.right_bases, q2.right_bases)?;
+    writeln!(f, "<tr><td>Mott Running Sum</td><td>{}</td><td>{}</td></tr>", q1.mott_bases, q2.mott_bases)?;
+    writeln!(f, "</table>")?;
+
+    writeln!(f, "<h2>Base Masking</h2>")?;
+    writeln!(f, "<table>")?;
+    writeln!(f, "<tr><th>Metric</th><th>Value</th></tr>")?;
+    writeln!(f, "<tr><td>Masked Low Quality Bases</td><td>{}</td></tr>", report.masked_low_quality_bases)?;
+    writeln!(f, "<tr><td>Masked Adapter Window Bases</td><td>{}</td></tr>", report.masked_adapter_bases)?;
     writeln!(f, "</table>")?;
 
     writeln!(f, "<h2>PolyG Trimming</h2>")?;
@@ -59,6 +195,19 @@ pub fn write_html_report(path: &str, report: &Report, title: &str) -> io::Result
     writeln!(f, "<tr><td>Overlap Pairs</td><td>{}</td></tr>", report.pe_overlap_count)?;
     writeln!(f, "<tr><td>Average Diff</td><td>{:.3}</td></tr>", report.pe_overlap_avg_diff)?;
     writeln!(f, "</table>")?;
+
+    writeln!(f, "<h2>Overlap Correction</h2>")?;
+    writeln!(f, "<table>")?;
+    writeln!(f, "<tr><th>Metric</th><th>Read 1</th><th>Read 2</th></tr>")?;
+    let (c1, c2) = (&report.correction_r1, &report.correction_r2);
+    writeln!(f, "<tr><td>Corrected Mismatches</td><td>{}</td><td>{}</td></tr>", c1.corrected_bases, c2.corrected_bases)?;
+    writeln!(f, "<tr><td>N Filled</td><td>{}</td><td>{}</td></tr>", c1.n_filled_bases, c2.n_filled_bases)?;
+    writeln!(f, "<tr><td>Quality Merged</td><td>{}</td><td>{}</td></tr>", c1.boosted_bases, c2.boosted_bases)?;
+    for base in ['A', 'C', 'G', 'T'] {
+        let (n1, n2) = (c1.by_base.get(&base).copied().unwrap_or(0), c2.by_base.get(&base).copied().unwrap_or(0));
+        writeln!(f, "<tr><td>Changed to {}</td><td>{}</td><td>{}</td></tr>", base, n1, n2)?;
+    }
+    writeln!(f, "</table>")?;
     
     writeln!(f, "</body>")?;
     writeln!(f, "</html>")?;
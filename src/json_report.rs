@@ -0,0 +1,278 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use serde::Serialize;
+use crate::config::Cli;
+use crate::filter::{ReadStats, Report};
+use crate::metrics::Performance;
+
+/// The JSON report in upstream fastp's layout (what MultiQC parses), serialized straight to
+/// the file. Everything fastp-rs adds over upstream is kept under `fastp_rs`, as the flat `Report`.
+#[derive(Serialize)]
+struct UpstreamReport<'a> {
+    summary: Summary,
+    filtering_result: FilteringResult,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duplication: Option<Duplication>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    insert_size: Option<InsertSize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    adapter_cutting: Option<AdapterCutting<'a>>,
+    read1_before_filtering: ReadSection,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    read2_before_filtering: Option<ReadSection>,
+    read1_after_filtering: ReadSection,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    read2_after_filtering: Option<ReadSection>,
+    command: &'a str,
+    performance: &'a Performance,
+    fastp_rs: &'a Report,
+}
+
+#[derive(Serialize)]
+struct Summary {
+    fastp_version: &'static str,
+    sequencing: String,
+    before_filtering: SummaryStats,
+    after_filtering: SummaryStats,
+}
+
+#[derive(Serialize)]
+struct SummaryStats {
+    total_reads: u64,
+    total_bases: u64,
+    q20_bases: u64,
+    q30_bases: u64,
+    q20_rate: f64,
+    q30_rate: f64,
+    read1_mean_length: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    read2_mean_length: Option<usize>,
+    gc_content: f64,
+}
+
+impl SummaryStats {
+    fn new(r1: &ReadStats, r2: Option<&ReadStats>) -> Self {
+        let mut all = r1.clone();
+        if let Some(r2) = r2 {
+            all.merge(r2);
+        }
+        Self {
+            total_reads: all.reads,
+            total_bases: all.bases,
+            q20_bases: all.q20_bases,
+            q30_bases: all.q30_bases,
+            q20_rate: all.rate(all.q20_bases),
+            q30_rate: all.rate(all.q30_bases),
+            read1_mean_length: r1.mean_len_floor(),
+            read2_mean_length: r2.map(ReadStats::mean_len_floor),
+            gc_content: all.rate(all.gc_bases),
+        }
+    }
+}
+
+/// Upstream counts reads, so a filtered pair counts twice. Routed adapter dimers and reads
+/// without a primer are discarded too, so they have categories of their own (when those
+/// options are on) and the categories add up to the reads taken in.
+#[derive(Serialize)]
+struct FilteringResult {
+    passed_filter_reads: u64,
+    low_quality_reads: u64,
+    #[serde(rename = "too_many_N_reads")]
+    too_many_n_reads: u64,
+    too_short_reads: u64,
+    too_long_reads: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    adapter_dimer_reads: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    no_primer_reads: Option<u64>,
+}
+
+impl FilteringResult {
+    fn new(report: &Report, cli: &Cli, mates: u64) -> Self {
+        Self {
+            passed_filter_reads: report.passed_reads * mates,
+            low_quality_reads: (report.failed_low_quality + report.failed_low_average_qual + report.failed_low_mean_qual) * mates,
+            too_many_n_reads: report.failed_n_excess * mates,
+            too_short_reads: report.failed_too_short * mates,
+            too_long_reads: 0,
+            adapter_dimer_reads: cli.adapter_dimer_out.is_some().then_some(report.adapter_dimer_reads * mates),
+            no_primer_reads: (cli.primers.is_some() && cli.primer_discard_unmatched).then_some(report.failed_no_primer * mates),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Duplication {
+    rate: f64,
+}
+
+#[derive(Serialize)]
+struct InsertSize {
+    peak: usize,
+    unknown: u64,
+    histogram: Vec<u64>,
+}
+
+#[derive(Serialize)]
+struct AdapterCutting<'a> {
+    adapter_trimmed_reads: u64,
+    adapter_trimmed_bases: u64,
+    read1_adapter_sequence: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    read2_adapter_sequence: Option<&'a str>,
+    read1_adapter_counts: &'a BTreeMap<String, u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    read2_adapter_counts: Option<&'a BTreeMap<String, u64>>,
+}
+
+#[derive(Serialize)]
+struct ReadSection {
+    total_reads: u64,
+    total_bases: u64,
+    q20_bases: u64,
+    q30_bases: u64,
+    total_cycles: usize,
+    quality_curves: QualityCurves,
+    content_curves: ContentCurves,
+}
+
+/// Mean quality per cycle, of each base and overall.
+#[derive(Serialize)]
+#[allow(non_snake_case)]
+struct QualityCurves {
+    A: Vec<f64>,
+    T: Vec<f64>,
+    C: Vec<f64>,
+    G: Vec<f64>,
+    mean: Vec<f64>,
+}
+
+/// Share of each base per cycle.
+#[derive(Serialize)]
+#[allow(non_snake_case)]
+struct ContentCurves {
+    A: Vec<f64>,
+    T: Vec<f64>,
+    C: Vec<f64>,
+    G: Vec<f64>,
+    N: Vec<f64>,
+    GC: Vec<f64>,
+}
+
+impl ReadSection {
+    fn new(stats: &ReadStats) -> Self {
+        let ratio = |n: u64, d: u64| if d == 0 { 0.0 } else { n as f64 / d as f64 };
+        let quality = |i: usize| stats.cycles.iter().map(|c| ratio(c.qual_sums[i], c.bases[i])).collect();
+        let content = |i: usize| stats.cycles.iter().map(|c| ratio(c.bases[i], c.bases.iter().sum())).collect();
+        Self {
+            total_reads: stats.reads,
+            total_bases: stats.bases,
+            q20_bases: stats.q20_bases,
+            q30_bases: stats.q30_bases,
+            total_cycles: stats.max_len,
+            quality_curves: QualityCurves {
+                A: quality(0),
+                T: quality(1),
+                C: quality(2),
+                G: quality(3),
+                mean: stats.cycles.iter().map(|c| ratio(c.qual_sums.iter().sum(), c.bases.iter().sum())).collect(),
+            },
+            content_curves: ContentCurves {
+                A: content(0),
+                T: content(1),
+                C: content(2),
+                G: content(3),
+                N: content(4),
+                GC: stats.cycles.iter().map(|c| ratio(c.bases[2] + c.bases[3], c.bases.iter().sum())).collect(),
+            },
+        }
+    }
+}
+
+fn adapter_name(adapter: &Option<String>) -> &str {
+    adapter.as_deref().filter(|a| !a.is_empty()).unwrap_or("unspecified")
+}
+
+pub fn write_json_report(path: &str, report: &Report, cli: &Cli, command: &str, performance: &Performance) -> io::Result<()> {
+    let paired = report.before_r2.reads > 0;
+    let mates = if paired { 2 } else { 1 };
+    let sequencing = if paired {
+        format!("paired end ({} cycles + {} cycles)", report.before_r1.max_len, report.before_r2.max_len)
+    } else {
+        format!("single end ({} cycles)", report.before_r1.max_len)
+    };
+
+    let upstream = UpstreamReport {
+        summary: Summary {
+            fastp_version: env!("CARGO_PKG_VERSION"),
+            sequencing,
+            before_filtering: SummaryStats::new(&report.before_r1, paired.then_some(&report.before_r2)),
+            after_filtering: SummaryStats::new(&report.after_r1, paired.then_some(&report.after_r2)),
+        },
+        filtering_result: FilteringResult::new(report, cli, mates),
+        duplication: report.duplication_rate.map(|rate| Duplication { rate }),
+        insert_size: paired.then(|| InsertSize {
+            peak: report.insert_sizes.iter().max_by_key(|&(size, n)| (n, std::cmp::Reverse(size))).map_or(0, |(&size, _)| size),
+            unknown: report.insert_size_unknown,
+            histogram: (0..=report.insert_sizes.keys().next_back().copied().unwrap_or(0))
+                .map(|size| report.insert_sizes.get(&size).copied().unwrap_or(0))
+                .collect(),
+        }),
+        adapter_cutting: (!cli.disable_adapter_trimming).then(|| AdapterCutting {
+            adapter_trimmed_reads: report.adapter_trimmed_reads,
+            adapter_trimmed_bases: report.adapter_trimmed_bases,
+            read1_adapter_sequence: adapter_name(&cli.adapter_sequence),
+            read2_adapter_sequence: paired.then_some(adapter_name(&cli.adapter_sequence_r2)),
+            read1_adapter_counts: &report.adapter_r1.adapter_counts,
+            read2_adapter_counts: paired.then_some(&report.adapter_r2.adapter_counts),
+        }),
+        read1_before_filtering: ReadSection::new(&report.before_r1),
+        read2_before_filtering: paired.then(|| ReadSection::new(&report.before_r2)),
+        read1_after_filtering: ReadSection::new(&report.after_r1),
+        read2_after_filtering: paired.then(|| ReadSection::new(&report.after_r2)),
+        command,
+        performance,
+        fastp_rs: report,
+    };
+    let mut w = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(&mut w, &upstream)?;
+    w.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn filtering_categories_add_up_to_the_reads_taken_in() {
+        let report = Report {
+            total_reads: 100,
+            passed_reads: 60,
+            failed_low_quality: 5,
+            failed_low_average_qual: 4,
+            failed_n_excess: 3,
+            failed_too_short: 10,
+            adapter_dimer_reads: 8,
+            failed_no_primer: 10,
+            ..Report::default()
+        };
+        let cli = Cli::parse_from(["fastp_rs", "--adapter_dimer_out", "dimers.fq", "--primers", "primers.fa", "--primer_discard_unmatched"]);
+        let result = FilteringResult::new(&report, &cli, 2);
+        let categories = [
+            result.passed_filter_reads,
+            result.low_quality_reads,
+            result.too_many_n_reads,
+            result.too_short_reads,
+            result.too_long_reads,
+            result.adapter_dimer_reads.unwrap(),
+            result.no_primer_reads.unwrap(),
+        ];
+        assert_eq!(categories.iter().sum::<u64>(), report.total_reads * 2);
+
+        // without those options nothing is discarded for them, and the categories are left out
+        let result = FilteringResult::new(&report, &Cli::parse_from(["fastp_rs"]), 1);
+        assert_eq!((result.adapter_dimer_reads, result.no_primer_reads), (None, None));
+    }
+}
@@ -0,0 +1,493 @@
+//! fastp-rs as a library: the pipeline behind the `fastp_rs` binary, for builds that add
+//! their own trimming stages. Implement `Processor`, `register` it on `Registry::builtin()`,
+//! name it in `--trim_order`, and hand the registry to `run`.
+
+mod config;
+mod fastq;
+mod filter;
+mod threading;
+mod html_report;
+mod json_report;
+mod compress;
+mod subsample;
+mod metrics;
+mod scheduler;
+
+pub use crate::config::Cli;
+pub use crate::fastq::FastqRecord;
+pub use crate::filter::{Processor, Registry, Report, TrimState, TrimmedRead};
+
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use std::collections::BinaryHeap;
+use std::cmp::Ordering;
+use crossbeam::channel::{bounded, Receiver, RecvTimeoutError, Sender};
+
+use crate::fastq::{QualityBins, Reader, RecordFormat, Writer};
+use crate::filter::{Discard, Filter, InputFileReads};
+use crate::threading::{Pack, PackLimit, Packer, ProcessedPack, DEFAULT_PACK_SIZE, LONG_READ_PACK_BASES};
+use crate::subsample::Subsampler;
+use crate::metrics::Metrics;
+use crate::scheduler::{CloseOnDrop, Scheduler};
+use crate::html_report::write_html_report;
+use crate::json_report::write_json_report;
+use crate::compress::{BlockFormat, CompressOptions, CompressionPool};
+
+// Helper for ordering ProcessedPack in BinaryHeap (MinHeap)
+struct OrderedPack(ProcessedPack);
+
+impl PartialEq for OrderedPack {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.id == other.0.id
+    }
+}
+impl Eq for OrderedPack {}
+impl PartialOrd for OrderedPack {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for OrderedPack {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Reverse order for MinHeap
+        other.0.id.cmp(&self.0.id)
+    }
+}
+
+/// Run fastp-rs with `cli` (already parsed), building the trimming stages from `registry`.
+pub fn run(mut cli: Cli, registry: &Registry) -> io::Result<()> {
+    let thread_num = if cli.thread == 0 { num_cpus::get() } else { cli.thread };
+    let autotune = !cli.disable_autotune;
+    // a given pack size is kept; otherwise packs are sized by bases, tuned while running
+    let pack_limit = if cli.pack_size > 0 {
+        PackLimit::Reads(cli.pack_size)
+    } else if cli.pack_bases > 0 {
+        PackLimit::Bases(cli.pack_bases)
+    } else if autotune {
+        PackLimit::Tuned
+    } else if cli.long_read {
+        PackLimit::Bases(LONG_READ_PACK_BASES)
+    } else {
+        PackLimit::Reads(DEFAULT_PACK_SIZE)
+    };
+    let pack_bases = match pack_limit {
+        PackLimit::Reads(_) => Some(0),
+        PackLimit::Bases(n) => Some(n),
+        PackLimit::Tuned => None,
+    };
+
+    // queue capacity; the scheduler keeps fewer packs in flight while fewer workers run
+    let qd = if cli.queue_depth > 0 { cli.queue_depth } else if autotune { thread_num * 4 } else { thread_num * 2 };
+    // one inflate thread keeps up with roughly four workers
+    let decompress_threads = if cli.decompress_threads == 0 { (thread_num / 4).clamp(1, 8) } else { cli.decompress_threads };
+    let (tx_pack, rx_pack): (Sender<Pack>, Receiver<Pack>) = bounded(qd);
+    let (tx_out, rx_out): (Sender<ProcessedPack>, Receiver<ProcessedPack>) = bounded(qd);
+
+    // Open inputs before the writer: a uBAM header is carried over to BAM outputs
+    cli.expand_inputs()?;
+    let mut r1 = Reader::chain(&cli.in1, cli.stdin, decompress_threads)?.with_fasta_quality(cli.fasta_default_qual).with_multi_line(cli.long_read);
+    let mut r2 = if !cli.in1.is_empty() && !cli.in2.is_empty() {
+        Some(Reader::chain(&cli.in2, false, decompress_threads)?.with_fasta_quality(cli.fasta_default_qual).with_multi_line(cli.long_read))
+    } else {
+        None
+    };
+    // BGZF inflate threads come out of the `-w` budget, the rest goes to workers and compression
+    let decode_threads = r1.decode_threads() + r2.as_ref().map_or(0, Reader::decode_threads);
+    let budget = thread_num.saturating_sub(decode_threads).max(1);
+    let scheduler = Arc::new(Scheduler::new(autotune, budget, pack_bases, (cli.queue_depth > 0).then_some(qd), qd));
+    if r1.is_fasta() || r2.as_ref().is_some_and(|r| r.is_fasta()) {
+        let ignored = cli.disable_quality_filters();
+        if !ignored.is_empty() {
+            eprintln!("Warning: FASTA input has no base qualities, ignoring {}", ignored.join(", "));
+        }
+    }
+    let paired = r2.is_some() || r1.is_interleaved();
+    if cli.subsample_count > 0 && cli.subsample_fraction > 0.0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "use either --subsample_fraction or --subsample_count"));
+    }
+    if !(0.0..=1.0).contains(&cli.subsample_fraction) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "--subsample_fraction must be between 0 and 1"));
+    }
+    if cli.long_read && paired {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "long-read mode takes single-end input only"));
+    }
+    let bam_header = r1.bam_header().unwrap_or_default().to_string();
+    let bam_out = cli.out1.as_deref().map(|p| RecordFormat::for_path(p) == RecordFormat::Bam).unwrap_or(false);
+    if cli.out2.as_deref().map(|p| RecordFormat::for_path(p) == RecordFormat::Bam || bam_out).unwrap_or(false) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "BAM output holds both mates: write it with --out1 only"));
+    }
+    if cli.adapter_dimer_out.as_deref().is_some_and(|p| RecordFormat::for_path(p) == RecordFormat::Bam) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "--adapter_dimer_out takes FASTQ or FASTA, not BAM"));
+    }
+
+    // qualities are binned once a read passes, so filtering uses the original ones and the
+    // after-filtering stats describe what is written
+    let quality_bins = cli.quality_bins.as_deref().map(QualityBins::parse).transpose()?.map(Arc::new);
+    let quality_binning = quality_bins.as_ref().map(|b| b.scheme().to_string());
+    let filter = Arc::new(Filter::new(cli.clone(), registry)?);
+    let route_dimers = cli.adapter_dimer_out.is_some();
+    let metrics = Arc::new(Metrics::new(qd, qd));
+    let tuner = scheduler.spawn(metrics.clone());
+    
+    // 1. Workers (as many as the scheduler lets in take packs)
+    let mut workers = Vec::new();
+    for index in 0..thread_num {
+        let rx = rx_pack.clone();
+        let tx = tx_out.clone();
+        let filter = filter.clone();
+        let quality_bins = quality_bins.clone();
+        let metrics = metrics.clone();
+        let gate = scheduler.workers.clone();
+        
+        let handle = thread::spawn(move || {
+            loop {
+                // time parked by the scheduler counts as waiting
+                metrics.worker.blocked(|| gate.wait_turn(index));
+                let Ok(pack) = metrics.worker.blocked(|| rx.recv()) else { break };
+                let start = Instant::now();
+                let reads = pack.data.len() as u64;
+                let mut processed_data = Vec::with_capacity(pack.data.len());
+                let mut local_report = Report::default();
+                let mut dimers = Vec::new();
+                
+                for (r1, r2_opt) in pack.data {
+                    local_report.total_reads += 1;
+                    
+                    // dimers are written untrimmed, so keep the originals when they are routed
+                    let original = route_dimers.then(|| (r1.clone(), r2_opt.clone()));
+                    local_report.before_r1.add(&r1);
+                    if let Some(r2) = &r2_opt { local_report.before_r2.add(r2); }
+                    filter.count_duplicate(&r1, r2_opt.as_ref(), &mut local_report);
+                    if let Some(r2) = r2_opt {
+                        // PE Processing
+                        let (mut rec1, mut rec2) = match filter.trim_pair(r1, r2, &mut local_report) {
+                            Ok(pair) => pair,
+                            Err(Discard::AdapterDimer) => { dimers.extend(original); continue }
+                            Err(Discard::Filtered) => continue,
+                        };
+
+                        if filter.pass_filters(&rec1, &mut local_report) && filter.pass_filters(&rec2, &mut local_report) {
+                            if let Some(bins) = &quality_bins {
+                                bins.apply(&mut rec1);
+                                bins.apply(&mut rec2);
+                            }
+                            local_report.passed_reads += 1;
+                            local_report.after_r1.add(&rec1);
+                            local_report.after_r2.add(&rec2);
+                            processed_data.push((rec1, Some(rec2)));
+                        }
+                    } else {
+                        // SE Processing
+                        let mut rec1 = match filter.trim_record(r1, &mut local_report) {
+                            Ok(rec) => rec,
+                            Err(Discard::AdapterDimer) => { dimers.extend(original); continue }
+                            Err(Discard::Filtered) => continue,
+                        };
+                        if filter.pass_filters(&rec1, &mut local_report) {
+                            if let Some(bins) = &quality_bins { bins.apply(&mut rec1); }
+                            local_report.passed_reads += 1;
+                            local_report.after_r1.add(&rec1);
+                            if filter.long_read() { local_report.add_read_length(rec1.seq.len()); }
+                            processed_data.push((rec1, None));
+                        }
+                    }
+                }
+                
+                metrics.worker.add_busy(start.elapsed());
+                metrics.worker.add_item();
+                metrics.add_reads(reads, local_report.before_r1.bases + local_report.before_r2.bases);
+                metrics.processed.sample(tx.len());
+                // the writer is gone (output error): stop, main reports its error
+                let processed = ProcessedPack {
+                    id: pack.id,
+                    data: processed_data,
+                    dimers,
+                    report: local_report,
+                };
+                if !metrics.worker.blocked(|| tx.send(processed).is_ok()) {
+                    break;
+                }
+            }
+        });
+        workers.push(handle);
+    }
+    
+    // Drop original tx_out so receiver closes when workers finish,
+    // and rx_pack so the reader notices when all workers have stopped
+    drop(tx_out);
+    drop(rx_pack);
+
+    // 2. Writer Thread
+    let cli_writer = cli.clone();
+    let final_report = Arc::new(Mutex::new(Report::default()));
+    let final_report_clone = final_report.clone();
+    let writer_metrics = metrics.clone();
+    let writer_scheduler = scheduler.clone();
+    
+    let writer_handle = thread::spawn(move || -> io::Result<()> {
+        let _close = CloseOnDrop(writer_scheduler.clone());
+        // compression pool for .gz/.bgz/.zst outputs when not using external compressors
+        let format1 = cli_writer.out1.as_deref().and_then(|p| BlockFormat::for_path(p, cli_writer.bgzf));
+        let format2 = cli_writer.out2.as_deref().and_then(|p| BlockFormat::for_path(p, cli_writer.bgzf));
+        let format_dimers = cli_writer.adapter_dimer_out.as_deref().and_then(|p| BlockFormat::for_path(p, cli_writer.bgzf));
+        let use_pool = !cli_writer.pigz && (format1.is_some() || format2.is_some() || format_dimers.is_some());
+        let opts = CompressOptions { level: cli_writer.compression, zstd_level: cli_writer.zstd_level, zstd_long: cli_writer.zstd_long };
+        let pool = if use_pool {
+            writer_scheduler.set_compressing();
+            Some(Arc::new(CompressionPool::new(thread_num, opts, writer_metrics.clone(), writer_scheduler.compressors.clone())))
+        } else {
+            None
+        };
+        let external_threads = if cli_writer.pigz_threads == 0 { thread_num } else { cli_writer.pigz_threads };
+        let open = |path: &str, stdout: bool, format: Option<BlockFormat>| -> io::Result<Writer> {
+            match (&pool, format) {
+                (_, Some(fmt)) if cli_writer.pigz => Writer::external(Some(path), stdout, fmt, &opts, external_threads, cli_writer.bgzf_index),
+                (Some(pool), Some(fmt)) => Writer::with_pool(Some(path), stdout, pool.clone(), fmt, cli_writer.bgzf_index),
+                _ => Writer::new(Some(path), stdout, cli_writer.compression),
+            }
+        };
+        let mut w1 = if let Some(p) = &cli_writer.out1 {
+            let w = open(p, cli_writer.stdout, format1)?;
+            Some(if bam_out { w.into_bam(&bam_header)? } else { w.with_format(RecordFormat::for_path(p)) })
+        } else if cli_writer.stdout {
+             Some(Writer::new(None, true, cli_writer.compression)?)
+        } else {
+            None
+        };
+        
+        let mut w2 = if let Some(p) = &cli_writer.out2 {
+            Some(open(p, false, format2)?.with_format(RecordFormat::for_path(p)))
+        } else {
+            None
+        };
+
+        // adapter dimers, mates interleaved
+        let mut wd = match &cli_writer.adapter_dimer_out {
+            Some(p) => Some(open(p, false, format_dimers)?.with_format(RecordFormat::for_path(p))),
+            None => None,
+        };
+
+        let mut next_id = 0;
+        let mut buffer = BinaryHeap::new();
+
+        while let Ok(pack) = writer_metrics.writer.blocked(|| rx_out.recv()) {
+            let start = Instant::now();
+            buffer.push(OrderedPack(pack));
+            
+            while let Some(top) = buffer.peek() {
+                if top.0.id == next_id {
+                    let OrderedPack(p) = buffer.pop().unwrap();
+                    
+                    // Aggregate Report
+                    {
+                        let mut rep = final_report_clone.lock().unwrap();
+                        rep.merge(&p.report);
+                    }
+                    
+                    // Write Output (compressed writers hand full blocks to the pool)
+                    for (r1, r2_opt) in p.data {
+                        if let (Some(r2), true) = (&r2_opt, bam_out) {
+                            if let Some(w) = &mut w1 { w.write_pair(&r1, r2)?; }
+                            continue;
+                        }
+                        if let Some(w) = &mut w1 { w.write_record(&r1)?; }
+                        if let Some(r2) = r2_opt { if let Some(w) = &mut w2 { w.write_record(&r2)?; } }
+                    }
+                    if let Some(w) = &mut wd {
+                        for (r1, r2_opt) in &p.dimers {
+                            w.write_record(r1)?;
+                            if let Some(r2) = r2_opt { w.write_record(r2)?; }
+                        }
+                    }
+                    next_id += 1;
+                    writer_metrics.writer.add_item();
+                    writer_scheduler.release_pack();
+                } else {
+                    break;
+                }
+            }
+            writer_metrics.writer.add_busy(start.elapsed());
+        }
+
+        // flushing waits for the last compressed blocks
+        writer_metrics.writer.busy(|| -> io::Result<()> {
+            if let Some(w) = w1 { w.finish()?; }
+            if let Some(w) = w2 { w.finish()?; }
+            if let Some(w) = wd { w.finish()?; }
+            Ok(())
+        })
+    });
+
+    // 3. Reader (Main Thread)
+    // long reads are packed by bases so a pack is a similar amount of work
+    let mut packer = Packer::new(tx_pack, pack_limit, scheduler.clone(), metrics.clone());
+    let progress = (cli.progress_interval > 0).then(|| spawn_progress(metrics.clone(), Duration::from_secs(cli.progress_interval)));
+    let mut sampler = Subsampler::new(&cli);
+    let mut reads_read = 0u64;
+    // mates that fall out of step end the run with an error once the pipeline has drained
+    let mut mismatch = None;
+
+    loop {
+        if cli.reads_to_process > 0 && reads_read >= cli.reads_to_process { break; }
+        let item = if paired {
+            // PE: two inputs, or mates interleaved in one paired uBAM
+            let (rec1_opt, rec2_opt) = match &mut r2 {
+                Some(r2) => (r1.next_record()?, r2.next_record()?),
+                None => r1.next_pair()?,
+            };
+
+            match (rec1_opt, rec2_opt) {
+                (Some(rec1), Some(rec2)) => {
+                    // chained inputs must switch files together, or the mates drift apart
+                    if let Some(r2) = r2.as_ref().filter(|r2| r2.file_index() != r1.file_index()) {
+                        let f = r1.file_index().min(r2.file_index());
+                        mismatch = Some(format!("PE inputs {} and {} have different numbers of reads", r1.file_counts()[f].0, r2.file_counts()[f].0));
+                        break;
+                    }
+                    (rec1, Some(rec2))
+                },
+                (None, None) => break,
+                _ => {
+                    mismatch = Some("PE inputs have different numbers of reads".to_string());
+                    break;
+                }
+            }
+        } else {
+            // SE
+            match r1.next_record()? {
+                Some(rec1) => (rec1, None),
+                None => break,
+            }
+        };
+        reads_read += 1;
+
+        if let Some(item) = sampler.offer(item) {
+            if !packer.push(item) { break; }
+        }
+    }
+
+    // a reservoir sample is only known once the input is exhausted
+    for item in sampler.finish() {
+        if !packer.push(item) { break; }
+    }
+
+    let mut input_files: Vec<InputFileReads> = r1.file_counts().iter().map(InputFileReads::from).collect();
+    if let Some(r2) = &r2 {
+        input_files.extend(r2.file_counts().iter().map(InputFileReads::from));
+    }
+
+    // Send remaining data and close the pack channel to notify workers
+    packer.finish();
+    metrics.finish_reader();
+    // parked workers are let in to see the pack queue closing
+    scheduler.workers.close();
+    
+    // Wait for workers
+    for w in workers {
+        w.join().unwrap();
+    }
+    
+    // Wait for writer
+    let written = writer_handle.join().unwrap();
+    drop(tuner.0);
+    tuner.1.join().unwrap();
+    written?;
+    // dropping the sender stops the progress thread
+    if let Some((stop, handle)) = progress {
+        drop(stop);
+        handle.join().unwrap();
+    }
+    if let Some(msg) = mismatch {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+
+    // Generate JSON Report
+    let command = std::env::args().collect::<Vec<_>>().join(" ");
+    let mut rep = final_report.lock().unwrap();
+    rep.input_files = input_files;
+    rep.quality_binning = quality_binning;
+    rep.finalize();
+    if !cli.dont_eval_duplication {
+        rep.duplication_rate = Some(rep.duplicated_reads as f64 / rep.total_reads.max(1) as f64);
+    }
+    // the threads the scheduler left running; parked ones only waited
+    let tuning = scheduler.report();
+    metrics.set_threads(1, tuning.workers, 1, tuning.compression_threads);
+    let mut performance = metrics.report();
+    performance.tuning = Some(tuning);
+    write_json_report(&cli.json, &rep, &cli, &command, &performance)?;
+
+    // Generate HTML Report
+    let hf = cli.html;
+    if !hf.is_empty() {
+        write_html_report(&hf, &rep, &cli.report_title)?;
+    }
+    
+    Ok(())
+}
+
+/// Print a progress line to stderr every `interval` until the returned sender is dropped.
+fn spawn_progress(metrics: Arc<Metrics>, interval: Duration) -> (Sender<()>, thread::JoinHandle<()>) {
+    let (stop, stopped) = bounded::<()>(0);
+    let handle = thread::spawn(move || {
+        while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+            eprintln!("{}", metrics.progress_line());
+        }
+    });
+    (stop, handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::fs;
+    use std::io::Read;
+    use std::path::Path;
+
+    fn decode(path: &Path) -> String {
+        let mut text = String::new();
+        let file = fs::File::open(path).unwrap();
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("zst") => zstd::Decoder::new(file).unwrap().read_to_string(&mut text),
+            _ => flate2::read::MultiGzDecoder::new(file).read_to_string(&mut text),
+        }
+        .unwrap();
+        text
+    }
+
+    #[test]
+    fn dimer_output_is_compressed_with_plain_outputs() {
+        let dir = std::env::temp_dir().join(format!("fastp_rs_dimer_out_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let r2_adapter = "AGATCGGAAGAGCGTCGTGTAGGGAAAGAGTGTAGATCTCGGTGGTCGCCGTATCATT";
+        let (mut in1, mut in2) = (String::new(), String::new());
+        for i in 0..10 {
+            let (s1, s2) = if i % 2 == 0 {
+                (format!("ACG{}", "AGATCGGAAGAGCACACGTCTGAACTCCAGTCA"), format!("CGT{r2_adapter}"))
+            } else {
+                ("ACGTTGCAGGTC".repeat(5), "TTGACCAGTGCA".repeat(5))
+            };
+            in1.push_str(&format!("@r{i}/1\n{s1}\n+\n{}\n", "I".repeat(s1.len())));
+            in2.push_str(&format!("@r{i}/2\n{s2}\n+\n{}\n", "I".repeat(s2.len())));
+        }
+        fs::write(path("in1.fq"), in1).unwrap();
+        fs::write(path("in2.fq"), in2).unwrap();
+
+        for dimers in ["dimers.fq.zst", "dimers.fq.bgz", "dimers.fq.gz"] {
+            let cli = Cli::parse_from([
+                "fastp_rs", "-i", &path("in1.fq"), "-I", &path("in2.fq"), "-o", &path("out1.fq"), "-O", &path("out2.fq"),
+                "--adapter_dimer_out", &path(dimers), "-j", &path("report.json"), "--html", &path("report.html"),
+            ]);
+            run(cli, &Registry::builtin()).unwrap();
+            let text = decode(&dir.join(dimers));
+            let names: Vec<&str> = text.lines().step_by(4).collect();
+            assert_eq!(names, ["@r0/1", "@r0/2", "@r2/1", "@r2/2", "@r4/1", "@r4/2", "@r6/1", "@r6/2", "@r8/1", "@r8/2"], "{dimers}");
+            assert_eq!(fs::read_to_string(path("out1.fq")).unwrap().lines().count(), 20);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
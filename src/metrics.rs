@@ -0,0 +1,280 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use crate::scheduler::Tuning;
+
+/// Busy and blocked time of one pipeline stage, summed over its threads.
+#[derive(Default)]
+pub struct StageTimer {
+    busy_ns: AtomicU64,
+    blocked_ns: AtomicU64,
+    /// packs (blocks, for compression) handled
+    items: AtomicU64,
+}
+
+impl StageTimer {
+    /// Run `f`, counting its time as work.
+    pub fn busy<T>(&self, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let out = f();
+        self.add_busy(start.elapsed());
+        out
+    }
+
+    /// Run `f`, counting its time as waiting on a queue.
+    pub fn blocked<T>(&self, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let out = f();
+        self.blocked_ns.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        out
+    }
+
+    pub fn add_busy(&self, time: Duration) {
+        self.busy_ns.fetch_add(time.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_item(&self) {
+        self.items.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn busy_secs(&self) -> f64 {
+        self.busy_ns.load(Ordering::Relaxed) as f64 / 1e9
+    }
+
+    fn blocked_time(&self) -> Duration {
+        Duration::from_nanos(self.blocked_ns.load(Ordering::Relaxed))
+    }
+
+    /// Every thread of a stage is either working or waiting (on a queue, or parked by the
+    /// scheduler), so the busy share is taken of the two together.
+    fn report(&self, threads: usize) -> StagePerformance {
+        let busy_s = self.busy_secs();
+        let blocked_s = self.blocked_ns.load(Ordering::Relaxed) as f64 / 1e9;
+        let thread_time = busy_s + blocked_s;
+        StagePerformance {
+            threads,
+            busy_s,
+            blocked_s,
+            busy_percent: if thread_time > 0.0 { busy_s * 100.0 / thread_time } else { 0.0 },
+            items: self.items.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Occupancy of a bounded queue, sampled whenever something is put on it.
+#[derive(Default)]
+pub struct QueueGauge {
+    capacity: AtomicUsize,
+    sum: AtomicU64,
+    samples: AtomicU64,
+    max: AtomicUsize,
+    last: AtomicUsize,
+}
+
+impl QueueGauge {
+    pub fn sample(&self, len: usize) {
+        self.sum.fetch_add(len as u64, Ordering::Relaxed);
+        self.samples.fetch_add(1, Ordering::Relaxed);
+        self.max.fetch_max(len, Ordering::Relaxed);
+        self.last.store(len, Ordering::Relaxed);
+    }
+
+    fn report(&self) -> QueuePerformance {
+        let samples = self.samples.load(Ordering::Relaxed);
+        QueuePerformance {
+            capacity: self.capacity.load(Ordering::Relaxed),
+            mean: if samples == 0 { 0.0 } else { self.sum.load(Ordering::Relaxed) as f64 / samples as f64 },
+            max: self.max.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Where the time of a run goes: each stage's busy and blocked time, how full the queues
+/// between them are, and the overall throughput. Shared by all pipeline threads.
+pub struct Metrics {
+    start: Instant,
+    pub reader: StageTimer,
+    pub worker: StageTimer,
+    pub writer: StageTimer,
+    pub compression: StageTimer,
+    /// reader → workers
+    pub packs: QueueGauge,
+    /// workers → writer
+    pub processed: QueueGauge,
+    reads: AtomicU64,
+    bases: AtomicU64,
+    threads: [AtomicUsize; 4],
+    /// `finish_reader` has recorded the reader's busy time
+    reader_done: AtomicBool,
+}
+
+impl Metrics {
+    pub fn new(pack_queue: usize, processed_queue: usize) -> Self {
+        let metrics = Self {
+            start: Instant::now(),
+            reader: StageTimer::default(),
+            worker: StageTimer::default(),
+            writer: StageTimer::default(),
+            compression: StageTimer::default(),
+            packs: QueueGauge::default(),
+            processed: QueueGauge::default(),
+            reads: AtomicU64::new(0),
+            bases: AtomicU64::new(0),
+            threads: Default::default(),
+            reader_done: AtomicBool::new(false),
+        };
+        metrics.packs.capacity.store(pack_queue, Ordering::Relaxed);
+        metrics.processed.capacity.store(processed_queue, Ordering::Relaxed);
+        metrics
+    }
+
+    /// Threads of the reader, worker, writer and compression stages (those running at the end).
+    pub fn set_threads(&self, reader: usize, workers: usize, writer: usize, compression: usize) {
+        for (slot, n) in self.threads.iter().zip([reader, workers, writer, compression]) {
+            slot.store(n, Ordering::Relaxed);
+        }
+    }
+
+    /// Count reads (pairs) taken by the workers.
+    pub fn add_reads(&self, reads: u64, bases: u64) {
+        self.reads.fetch_add(reads, Ordering::Relaxed);
+        self.bases.fetch_add(bases, Ordering::Relaxed);
+    }
+
+    /// Running totals for the scheduler. The reader's busy time is only known at its end, so
+    /// until then it is taken as its running time less its waits.
+    pub fn snapshot(&self) -> Snapshot {
+        let reader_busy = if self.reader_done.load(Ordering::Acquire) {
+            self.reader.busy_secs()
+        } else {
+            self.start.elapsed().saturating_sub(self.reader.blocked_time()).as_secs_f64()
+        };
+        Snapshot {
+            reads: self.reads.load(Ordering::Relaxed),
+            reader_busy,
+            worker_busy: self.worker.busy_secs(),
+            worker_items: self.worker.items.load(Ordering::Relaxed),
+            compression_busy: self.compression.busy_secs(),
+            compression_items: self.compression.items.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The reader's work is its running time less the time spent waiting on the workers.
+    pub fn finish_reader(&self) {
+        self.reader.add_busy(self.start.elapsed().saturating_sub(self.reader.blocked_time()));
+        self.reader_done.store(true, Ordering::Release);
+    }
+
+    pub fn report(&self) -> Performance {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let reads = self.reads.load(Ordering::Relaxed);
+        let bases = self.bases.load(Ordering::Relaxed);
+        let threads = |i: usize| self.threads[i].load(Ordering::Relaxed);
+        Performance {
+            elapsed_s: elapsed,
+            reads,
+            reads_per_s: if elapsed > 0.0 { reads as f64 / elapsed } else { 0.0 },
+            bases_per_s: if elapsed > 0.0 { bases as f64 / elapsed } else { 0.0 },
+            reader: self.reader.report(threads(0)),
+            worker: self.worker.report(threads(1)),
+            writer: self.writer.report(threads(2)),
+            compression: self.compression.report(threads(3)),
+            pack_queue: self.packs.report(),
+            processed_queue: self.processed.report(),
+            tuning: None,
+        }
+    }
+
+    /// One progress line for stderr.
+    pub fn progress_line(&self) -> String {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let reads = self.reads.load(Ordering::Relaxed);
+        format!(
+            "[{:.0}s] {} reads, {:.0} reads/s, queues {}/{} packs {}/{} processed",
+            elapsed,
+            reads,
+            if elapsed > 0.0 { reads as f64 / elapsed } else { 0.0 },
+            self.packs.last.load(Ordering::Relaxed),
+            self.packs.capacity.load(Ordering::Relaxed),
+            self.processed.last.load(Ordering::Relaxed),
+            self.processed.capacity.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// The `performance` section of the JSON report.
+#[derive(Serialize)]
+pub struct Performance {
+    pub elapsed_s: f64,
+    /// reads (pairs) processed
+    pub reads: u64,
+    pub reads_per_s: f64,
+    pub bases_per_s: f64,
+    pub reader: StagePerformance,
+    pub worker: StagePerformance,
+    pub writer: StagePerformance,
+    pub compression: StagePerformance,
+    pub pack_queue: QueuePerformance,
+    pub processed_queue: QueuePerformance,
+    /// the thread split, pack size and depth the scheduler ended with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tuning: Option<Tuning>,
+}
+
+/// Cumulative stage times (seconds) and counts at one moment.
+pub struct Snapshot {
+    pub reads: u64,
+    pub reader_busy: f64,
+    pub worker_busy: f64,
+    pub worker_items: u64,
+    pub compression_busy: f64,
+    /// blocks compressed
+    pub compression_items: u64,
+}
+
+#[derive(Serialize)]
+pub struct StagePerformance {
+    pub threads: usize,
+    /// seconds spent working and waiting on queues, summed over the threads
+    pub busy_s: f64,
+    pub blocked_s: f64,
+    /// busy share of the stage's thread time (busy and blocked)
+    pub busy_percent: f64,
+    pub items: u64,
+}
+
+#[derive(Serialize)]
+pub struct QueuePerformance {
+    pub capacity: usize,
+    pub mean: f64,
+    pub max: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn busy_share_counts_parked_threads_as_waiting() {
+        let timer = StageTimer::default();
+        timer.add_busy(Duration::from_secs(3));
+        // a second thread parked by the scheduler the whole time
+        timer.blocked_ns.fetch_add(Duration::from_secs(4).as_nanos() as u64, Ordering::Relaxed);
+        timer.blocked_ns.fetch_add(Duration::from_secs(1).as_nanos() as u64, Ordering::Relaxed);
+        let report = timer.report(1);
+        assert_eq!((report.threads, report.busy_s, report.blocked_s), (1, 3.0, 5.0));
+        assert!((report.busy_percent - 37.5).abs() < 1e-9);
+        assert_eq!(StageTimer::default().report(0).busy_percent, 0.0);
+    }
+
+    #[test]
+    fn reader_busy_time_stops_with_the_reader() {
+        let metrics = Metrics::new(4, 4);
+        metrics.reader.blocked(|| std::thread::sleep(Duration::from_millis(20)));
+        metrics.finish_reader();
+        let finished = metrics.snapshot().reader_busy;
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(metrics.snapshot().reader_busy, finished);
+        assert_eq!(metrics.reader.busy_secs(), finished);
+    }
+}
@@ -0,0 +1,352 @@
+//! Adaptive tuning of the pipeline: the `-w` thread budget is split between the workers
+//! and the compression threads by what each stage costs per read, packs are sized by bases
+//! so one takes about `TARGET_PACK_TIME` of worker time, and the packs in flight between the
+//! reader and the writer follow the number of active workers. Stage costs come from `Metrics`.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+use crossbeam::channel::{bounded, RecvTimeoutError, Sender};
+use serde::Serialize;
+use crate::metrics::{Metrics, Snapshot};
+
+/// Bases per pack when tuning starts, about 3500 pairs of 2x150 reads.
+pub const INITIAL_PACK_BASES: usize = 1 << 20;
+const MIN_PACK_BASES: usize = 64 << 10;
+const MAX_PACK_BASES: usize = 64 << 20;
+
+/// Worker time a pack should take: long enough to amortize the queues, short enough
+/// that the workers and the writer stay evenly fed.
+const TARGET_PACK_TIME: f64 = 0.02;
+
+/// How often the split is revised.
+const TICK: Duration = Duration::from_millis(200);
+
+/// Lets the first `active` threads of a group run; the others park until they are let in again.
+pub struct Gate {
+    active: AtomicUsize,
+    closed: AtomicBool,
+    lock: Mutex<()>,
+    cv: Condvar,
+}
+
+impl Gate {
+    pub fn new(active: usize) -> Self {
+        Self { active: AtomicUsize::new(active.max(1)), closed: AtomicBool::new(false), lock: Mutex::new(()), cv: Condvar::new() }
+    }
+
+    /// Wait until thread `index` may run (or the gate is closed for shutdown).
+    pub fn wait_turn(&self, index: usize) {
+        if index < self.active.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut guard = self.lock.lock().unwrap();
+        while index >= self.active.load(Ordering::Relaxed) && !self.closed.load(Ordering::Relaxed) {
+            guard = self.cv.wait_timeout(guard, Duration::from_millis(100)).unwrap().0;
+        }
+    }
+
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    fn set_active(&self, n: usize) {
+        let _guard = self.lock.lock().unwrap();
+        self.active.store(n.max(1), Ordering::Relaxed);
+        self.cv.notify_all();
+    }
+
+    /// Let every thread through, so parked ones see their queue closing and exit.
+    pub fn close(&self) {
+        let _guard = self.lock.lock().unwrap();
+        self.closed.store(true, Ordering::Relaxed);
+        self.cv.notify_all();
+    }
+}
+
+/// Counts packs between the reader and the writer, holding the reader back at the limit.
+struct InFlight {
+    count: Mutex<usize>,
+    limit: AtomicUsize,
+    /// the writer has stopped, nothing will be released any more
+    closed: AtomicBool,
+    cv: Condvar,
+}
+
+/// How the scheduler split the budget, for the `performance` report.
+#[derive(Serialize, Clone)]
+pub struct Tuning {
+    pub autotune: bool,
+    pub thread_budget: usize,
+    pub workers: usize,
+    pub compression_threads: usize,
+    /// bases per pack at the end (0 = fixed by `--pack_size`)
+    pub pack_bases: usize,
+    pub queue_depth: usize,
+    /// times the split, pack size or depth changed
+    pub adjustments: u64,
+}
+
+pub struct Scheduler {
+    autotune: bool,
+    budget: usize,
+    /// worker threads, and compression threads (none without a compression pool)
+    pub workers: Arc<Gate>,
+    pub compressors: Arc<Gate>,
+    compressing: AtomicBool,
+    pack_bases: AtomicUsize,
+    /// a fixed `--pack_size`/`--pack_bases`/`--queue_depth` is left alone
+    fixed_pack: bool,
+    fixed_depth: bool,
+    max_depth: usize,
+    in_flight: InFlight,
+    adjustments: AtomicU64,
+}
+
+/// Closes the scheduler when dropped, so the writer stopping for any reason releases the reader.
+pub struct CloseOnDrop(pub Arc<Scheduler>);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+impl Scheduler {
+    /// `budget` threads (`-w`) for workers and compression; `max_depth` is the capacity of the queues.
+    /// A `pack_bases` or `depth` of `None` is tuned (with `autotune`), given ones are kept
+    /// (packs of a fixed read count are `Some(0)`).
+    pub fn new(autotune: bool, budget: usize, pack_bases: Option<usize>, depth: Option<usize>, max_depth: usize) -> Self {
+        let budget = budget.max(1);
+        // workers start with the whole budget; compression threads are let in once their cost is known
+        let (workers, compressors) = if autotune { (budget, 1) } else { (budget, budget) };
+        let start_depth = depth.unwrap_or(if autotune { (2 * workers + 2).min(max_depth) } else { max_depth });
+        Self {
+            autotune,
+            budget,
+            workers: Arc::new(Gate::new(workers)),
+            compressors: Arc::new(Gate::new(compressors)),
+            compressing: AtomicBool::new(false),
+            pack_bases: AtomicUsize::new(pack_bases.unwrap_or(INITIAL_PACK_BASES)),
+            fixed_pack: pack_bases.is_some(),
+            fixed_depth: depth.is_some(),
+            max_depth,
+            in_flight: InFlight { count: Mutex::new(0), limit: AtomicUsize::new(start_depth.max(1)), closed: AtomicBool::new(false), cv: Condvar::new() },
+            adjustments: AtomicU64::new(0),
+        }
+    }
+
+    /// Output is compressed on the pool, whose threads share the budget.
+    pub fn set_compressing(&self) {
+        self.compressing.store(true, Ordering::Relaxed);
+    }
+
+    pub fn pack_bases(&self) -> usize {
+        self.pack_bases.load(Ordering::Relaxed)
+    }
+
+    /// Wait for room for one more pack; `false` once the writer has stopped.
+    pub fn acquire_pack(&self) -> bool {
+        let f = &self.in_flight;
+        let mut count = f.count.lock().unwrap();
+        while *count >= f.limit.load(Ordering::Relaxed) && !f.closed.load(Ordering::Relaxed) {
+            count = f.cv.wait(count).unwrap();
+        }
+        *count += 1;
+        !f.closed.load(Ordering::Relaxed)
+    }
+
+    /// A pack was written.
+    pub fn release_pack(&self) {
+        let f = &self.in_flight;
+        let mut count = f.count.lock().unwrap();
+        *count = count.saturating_sub(1);
+        f.cv.notify_one();
+    }
+
+    /// The writer is done (or failed): wake the reader, and the parked threads so they can exit.
+    pub fn close(&self) {
+        let f = &self.in_flight;
+        let _count = f.count.lock().unwrap();
+        f.closed.store(true, Ordering::Relaxed);
+        f.cv.notify_all();
+        self.workers.close();
+        self.compressors.close();
+    }
+
+    pub fn report(&self) -> Tuning {
+        let compressing = self.compressing.load(Ordering::Relaxed);
+        Tuning {
+            autotune: self.autotune,
+            thread_budget: self.budget,
+            workers: self.workers.active(),
+            compression_threads: if compressing { self.compressors.active() } else { 0 },
+            pack_bases: self.pack_bases(),
+            queue_depth: self.in_flight.limit.load(Ordering::Relaxed),
+            adjustments: self.adjustments.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Revise the split every `TICK` until the returned sender is dropped.
+    pub fn spawn(self: &Arc<Self>, metrics: Arc<Metrics>) -> (Sender<()>, thread::JoinHandle<()>) {
+        let (stop, stopped) = bounded::<()>(0);
+        let scheduler = self.clone();
+        let handle = thread::spawn(move || {
+            let mut last = metrics.snapshot();
+            let mut costs: Option<[f64; 3]> = None;
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(TICK) {
+                let now = metrics.snapshot();
+                // a tick without finished packs is folded into the next one
+                if scheduler.retune(&last, &now, &mut costs) {
+                    last = now;
+                }
+            }
+        });
+        (stop, handle)
+    }
+
+    /// One revision from the stage timings since `last`, `false` when there was nothing to
+    /// measure. `costs` are the smoothed seconds per read of the reader, the workers and compression.
+    fn retune(&self, last: &Snapshot, now: &Snapshot, costs: &mut Option<[f64; 3]>) -> bool {
+        let reads = now.reads.saturating_sub(last.reads);
+        let packs = now.worker_items.saturating_sub(last.worker_items);
+        if !self.autotune || reads == 0 || packs == 0 {
+            return false;
+        }
+        let per_read = |busy: f64, before: f64| (busy - before).max(0.0) / reads as f64;
+        // compression runs behind the workers (blocks queue in the writers), so what it did
+        // this tick belongs to earlier reads: its cost is taken per block, at the blocks per read so far
+        let blocks = now.compression_items.saturating_sub(last.compression_items);
+        let compression = if blocks > 0 {
+            (now.compression_busy - last.compression_busy).max(0.0) / blocks as f64 * now.compression_items as f64 / now.reads as f64
+        } else {
+            costs.map_or(0.0, |c| c[2])
+        };
+        let sample = [
+            per_read(now.reader_busy, last.reader_busy),
+            per_read(now.worker_busy, last.worker_busy),
+            compression,
+        ];
+        let smoothed = match costs {
+            Some(c) => [0, 1, 2].map(|i| (c[i] + sample[i]) / 2.0),
+            None => sample,
+        };
+        *costs = Some(smoothed);
+        let [reader, worker, compression] = smoothed;
+        let mut changed = false;
+
+        // threads in proportion to cost; the reader's share is left free for it
+        let compressing = self.compressing.load(Ordering::Relaxed);
+        let total = reader + worker + if compressing { compression } else { 0.0 };
+        if total > 0.0 {
+            let share = |cost: f64| (self.budget as f64 * cost / total).round() as usize;
+            let compressors = if compressing { share(compression).max(1) } else { 1 };
+            let workers = share(worker).clamp(1, self.budget.saturating_sub(compressing as usize).max(1));
+            for (gate, n) in [(&self.workers, workers), (&self.compressors, compressors)] {
+                if gate.active() != n {
+                    gate.set_active(n);
+                    changed = true;
+                }
+            }
+        }
+
+        if !self.fixed_pack {
+            // halfway to the size that takes `TARGET_PACK_TIME`, so one odd tick does not swing it
+            let pack_time = (now.worker_busy - last.worker_busy).max(0.0) / packs as f64;
+            let current = self.pack_bases() as f64;
+            if pack_time > 0.0 {
+                let target = current * TARGET_PACK_TIME / pack_time;
+                let next = ((current * target).sqrt() as usize).clamp(MIN_PACK_BASES, MAX_PACK_BASES);
+                // ignore changes under 10%
+                if next.abs_diff(current as usize) * 10 > current as usize {
+                    self.pack_bases.store(next, Ordering::Relaxed);
+                    changed = true;
+                }
+            }
+        }
+
+        if !self.fixed_depth {
+            // two packs per active worker keep them fed while the reader fills the next ones
+            let depth = (2 * self.workers.active() + 2).min(self.max_depth);
+            let f = &self.in_flight;
+            if f.limit.swap(depth, Ordering::Relaxed) != depth {
+                let _count = f.count.lock().unwrap();
+                f.cv.notify_all();
+                changed = true;
+            }
+        }
+
+        if changed {
+            self.adjustments.fetch_add(1, Ordering::Relaxed);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cumulative snapshots from per-tick reads, worker seconds, compressed blocks and compression seconds.
+    fn snapshots(ticks: &[(u64, f64, u64, f64)]) -> Vec<Snapshot> {
+        let mut now = Snapshot { reads: 0, reader_busy: 0.0, worker_busy: 0.0, worker_items: 0, compression_busy: 0.0, compression_items: 0 };
+        let mut out = vec![Snapshot { ..now }];
+        for &(reads, worker, blocks, compression) in ticks {
+            now.reads += reads;
+            now.reader_busy += reads as f64 * 1e-7;
+            now.worker_busy += worker;
+            now.worker_items += 10;
+            now.compression_items += blocks;
+            now.compression_busy += compression;
+            out.push(Snapshot { ..now });
+        }
+        out
+    }
+
+    fn split(scheduler: &Scheduler, ticks: &[(u64, f64, u64, f64)]) -> (usize, usize) {
+        let mut costs = None;
+        for pair in snapshots(ticks).windows(2) {
+            assert!(scheduler.retune(&pair[0], &pair[1], &mut costs));
+        }
+        (scheduler.workers.active(), scheduler.compressors.active())
+    }
+
+    fn compressing_scheduler() -> Scheduler {
+        let scheduler = Scheduler::new(true, 8, None, None, 32);
+        scheduler.set_compressing();
+        scheduler
+    }
+
+    #[test]
+    fn threads_follow_stage_costs() {
+        // 3 µs of worker time and 1 µs of compression per read (1 ms blocks of 1000 reads)
+        let steady = (100_000, 0.3, 100, 0.1);
+        assert_eq!(split(&compressing_scheduler(), &[steady; 4]), (6, 2));
+        // the other way round
+        let heavy_compression = (100_000, 0.1, 100, 0.3);
+        assert_eq!(split(&compressing_scheduler(), &[heavy_compression; 4]), (2, 6));
+        // without compression the workers get the whole budget
+        let scheduler = Scheduler::new(true, 8, None, None, 32);
+        assert_eq!(split(&scheduler, &[steady; 4]).0, 8);
+    }
+
+    #[test]
+    fn compression_backlog_does_not_take_the_workers_threads() {
+        // compression lags behind (1000 reads a block), then works off the blocks of earlier
+        // reads while the workers slow down; a cost per read of this tick would be 6 µs
+        let lagging = (100_000, 0.3, 50, 0.05);
+        let backlog = (10_000, 0.03, 60, 0.06);
+        let (workers, compressors) = split(&compressing_scheduler(), &[lagging, lagging, backlog, backlog]);
+        assert!(workers > 2 * compressors, "{workers} workers, {compressors} compressors");
+    }
+
+    #[test]
+    fn ticks_without_packs_are_not_measured() {
+        let scheduler = compressing_scheduler();
+        let s = snapshots(&[(0, 0.0, 5, 0.2)]);
+        let mut costs = None;
+        assert!(!scheduler.retune(&s[0], &s[1], &mut costs));
+        assert!(costs.is_none());
+    }
+}
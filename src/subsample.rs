@@ -0,0 +1,122 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::config::Cli;
+use crate::threading::ReadPair;
+
+/// Seeded read subsampling, done on the reader thread so the selection only
+/// depends on the input order and the seed, never on the number of workers.
+/// A pair is kept or dropped as a whole.
+pub enum Subsampler {
+    All,
+    /// keep each read (pair) with probability `fraction`
+    Fraction { rng: StdRng, fraction: f64 },
+    /// keep a uniform random sample of `size` reads (pairs), in input order
+    Reservoir { rng: StdRng, size: usize, seen: u64, kept: Vec<(u64, ReadPair)> },
+}
+
+impl Subsampler {
+    pub fn new(cli: &Cli) -> Self {
+        let rng = StdRng::seed_from_u64(cli.seed);
+        if cli.subsample_count > 0 {
+            Subsampler::Reservoir { rng, size: cli.subsample_count, seen: 0, kept: Vec::with_capacity(cli.subsample_count) }
+        } else if cli.subsample_fraction > 0.0 && cli.subsample_fraction < 1.0 {
+            Subsampler::Fraction { rng, fraction: cli.subsample_fraction }
+        } else {
+            Subsampler::All
+        }
+    }
+
+    /// Offer the next input read; returns it if it can be processed right away.
+    /// The reservoir holds its sample until `finish`.
+    pub fn offer(&mut self, item: ReadPair) -> Option<ReadPair> {
+        match self {
+            Subsampler::All => Some(item),
+            Subsampler::Fraction { rng, fraction } => (rng.random::<f64>() < *fraction).then_some(item),
+            Subsampler::Reservoir { rng, size, seen, kept } => {
+                // Algorithm R: the i-th read replaces a random slot with probability size/i
+                let index = *seen;
+                *seen += 1;
+                if kept.len() < *size {
+                    kept.push((index, item));
+                } else {
+                    let slot = rng.random_range(0..*seen) as usize;
+                    if slot < *size {
+                        kept[slot] = (index, item);
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    /// Reads still held back, in input order.
+    pub fn finish(self) -> Vec<ReadPair> {
+        match self {
+            Subsampler::Reservoir { mut kept, .. } => {
+                kept.sort_unstable_by_key(|(index, _)| *index);
+                kept.into_iter().map(|(_, item)| item).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use crate::fastq::FastqRecord;
+
+    fn pair(i: usize) -> ReadPair {
+        let rec = |mate: u8| FastqRecord { name: format!("@r{i}/{mate}"), seq: "ACGT".into(), plus: "+".into(), qual: "IIII".into() };
+        (rec(1), Some(rec(2)))
+    }
+
+    fn sample(args: &[&str], reads: usize) -> Vec<usize> {
+        let cli = Cli::parse_from(["fastp_rs"].iter().chain(args));
+        let mut sub = Subsampler::new(&cli);
+        let mut out: Vec<ReadPair> = (0..reads).filter_map(|i| sub.offer(pair(i))).collect();
+        out.extend(sub.finish());
+        out.iter()
+            .map(|(r1, r2)| {
+                assert_eq!(r2.as_ref().unwrap().name, r1.name.replace("/1", "/2"), "mates stay together");
+                r1.name[2..r1.name.len() - 2].parse().unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reservoir_keeps_a_fixed_sample_in_input_order() {
+        let kept = sample(&["--subsample_count", "100"], 10_000);
+        assert_eq!(kept.len(), 100);
+        assert!(kept.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(kept, sample(&["--subsample_count", "100"], 10_000), "same seed, same sample");
+        assert_ne!(kept, sample(&["--subsample_count", "100", "--seed", "12"], 10_000));
+        // the sample is spread over the whole input, not just its start
+        assert!(kept.iter().filter(|&&i| i >= 5_000).count() > 30);
+        // fewer reads than the sample size: all of them
+        assert_eq!(sample(&["--subsample_count", "100"], 42), (0..42).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn reservoir_sampling_is_uniform() {
+        // how often each of 20 reads lands in a sample of 5, over many seeds
+        let mut hits = [0u32; 20];
+        for seed in 0..2_000 {
+            for i in sample(&["--subsample_count", "5", "--seed", &seed.to_string()], 20) {
+                hits[i] += 1;
+            }
+        }
+        // expected 500 each
+        assert!(hits.iter().all(|&h| (400..600).contains(&h)), "{hits:?}");
+    }
+
+    #[test]
+    fn fraction_and_passthrough() {
+        let kept = sample(&["--subsample_fraction", "0.1"], 10_000);
+        assert!((800..1_200).contains(&kept.len()), "{}", kept.len());
+        assert_eq!(sample(&[], 10).len(), 10);
+        assert_eq!(sample(&["--subsample_fraction", "1.0"], 10).len(), 10);
+    }
+}
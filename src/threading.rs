@@ -1,16 +1,86 @@
+use std::sync::Arc;
+use crossbeam::channel::Sender;
+use crate::metrics::Metrics;
+use crate::scheduler::Scheduler;
 use crate::fastq::FastqRecord;
 use crate::filter::Report;
 
+/// (R1, R2) - R2 is None for SE
+pub type ReadPair = (FastqRecord, Option<FastqRecord>);
+
 pub struct Pack {
     pub id: u64,
-    pub data: Vec<(FastqRecord, Option<FastqRecord>)>, // (R1, R2) - R2 is None for SE
+    pub data: Vec<ReadPair>,
 }
 
 pub struct ProcessedPack {
     pub id: u64,
-    pub data: Vec<(FastqRecord, Option<FastqRecord>)>,
+    pub data: Vec<ReadPair>,
+    /// adapter dimers routed to `--adapter_dimer_out`, untrimmed
+    pub dimers: Vec<ReadPair>,
     pub report: Report,
 }
 
 // To maintain order, we can use a MinHeap or just a simple BTreeMap buffer in the writer
 // But since packs come from workers, they might be out of order.
+
+/// Reads per pack without tuning.
+pub const DEFAULT_PACK_SIZE: usize = 1000;
+/// Bases per pack for `--long_read` without tuning.
+pub const LONG_READ_PACK_BASES: usize = 10_000_000;
+
+/// When a pack is full.
+pub enum PackLimit {
+    Reads(usize),
+    Bases(usize),
+    /// the scheduler's current bases per pack
+    Tuned,
+}
+
+/// Groups reads into numbered packs for the workers, holding back while the scheduler
+/// has as many packs in flight as it allows.
+pub struct Packer {
+    tx: Sender<Pack>,
+    next_id: u64,
+    data: Vec<ReadPair>,
+    bases: usize,
+    limit: PackLimit,
+    scheduler: Arc<Scheduler>,
+    metrics: Arc<Metrics>,
+}
+
+impl Packer {
+    pub fn new(tx: Sender<Pack>, limit: PackLimit, scheduler: Arc<Scheduler>, metrics: Arc<Metrics>) -> Self {
+        Self { tx, next_id: 0, data: Vec::new(), bases: 0, limit, scheduler, metrics }
+    }
+
+    /// Add a read (pair); `false` once the workers are gone.
+    pub fn push(&mut self, item: ReadPair) -> bool {
+        self.bases += item.0.seq.len() + item.1.as_ref().map_or(0, |r| r.seq.len());
+        self.data.push(item);
+        let full = match self.limit {
+            PackLimit::Reads(max) => self.data.len() >= max,
+            PackLimit::Bases(max) => self.bases >= max,
+            PackLimit::Tuned => self.bases >= self.scheduler.pack_bases(),
+        };
+        !full || self.send()
+    }
+
+    /// Send the last, partial pack; dropping the packer then closes the channel.
+    pub fn finish(mut self) {
+        if !self.data.is_empty() {
+            self.send();
+        }
+    }
+
+    fn send(&mut self) -> bool {
+        let capacity = self.data.len();
+        let data = std::mem::replace(&mut self.data, Vec::with_capacity(capacity));
+        self.bases = 0;
+        let id = self.next_id;
+        self.next_id += 1;
+        self.metrics.packs.sample(self.tx.len());
+        self.metrics.reader.add_item();
+        self.metrics.reader.blocked(|| self.scheduler.acquire_pack() && self.tx.send(Pack { id, data }).is_ok())
+    }
+}